@@ -1,7 +1,8 @@
 use nom::bytes::complete::tag;
 use nom::character::complete::{alphanumeric1, multispace0};
 use nom::combinator::opt;
-use nom::sequence::{preceded, terminated, tuple};
+use nom::multi::many0;
+use nom::sequence::{preceded, terminated};
 
 use crate::assembler::instruction_parsers::{Action, AssemblerInstruction};
 use crate::assembler::label_parsers::label_declaration_parser;
@@ -17,22 +18,17 @@ fn directive_declaration_parse(input: &str) -> nom::IResult<&str, Token> {
 pub fn directive(input: &str) -> nom::IResult<&str, AssemblerInstruction> {
     let (input, label) = terminated(opt(label_declaration_parser), multispace0)(input)?;
     let (input, d) = terminated(directive_declaration_parse, multispace0)(input)?;
-    let (input, (op1, op2, op3)) = tuple((
-        terminated(opt(any_operand_parser), multispace0),
-        terminated(opt(any_operand_parser), multispace0),
-        terminated(opt(any_operand_parser), multispace0),
-    ))(input)?;
+    let (input, operands) = many0(terminated(any_operand_parser, multispace0))(input)?;
     let asm_instruction = AssemblerInstruction {
         label,
         action: Action::Directive(d),
-        operand1: op1,
-        operand2: op2,
-        operand3: op3,
+        operands,
     };
     Ok((input, asm_instruction))
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -54,9 +50,7 @@ mod tests {
         let correct_instruction = AssemblerInstruction {
             label: Some(Token::LabelDeclaration("test".to_string())),
             action: Action::Directive(Token::Directive("asciiz".to_string())),
-            operand1: Some(Token::StringOperand("Hello".to_string())),
-            operand2: None,
-            operand3: None,
+            operands: vec![Token::StringOperand("Hello".to_string())],
         };
 
         assert_eq!(directive, correct_instruction);
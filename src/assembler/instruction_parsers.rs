@@ -1,12 +1,18 @@
+use std::convert::TryFrom;
+
+use nom::branch::alt;
 use nom::character::complete::{multispace0, space1};
 use nom::combinator::opt;
-use nom::sequence::{terminated, tuple};
+use nom::sequence::terminated;
 
-use crate::assembler::label_parsers::label_declaration_parser;
+use crate::assembler::label_parsers::{label_declaration_parser, label_usage_parser};
 use crate::assembler::opcode_parsers::opcode_parser;
-use crate::assembler::operand_parsers::{integer_operand_parser, register_parser};
-use crate::assembler::Token;
-use crate::instructions::Opcode;
+use crate::assembler::operand_parsers::{
+    float_operand_parser, float_register_parser, integer_operand_parser, register_parser,
+    vector_register_parser,
+};
+use crate::assembler::{SymbolTable, Token};
+use crate::instructions::{Instruction, Operand, OperandKind};
 
 #[derive(Debug, PartialEq)]
 pub enum Action {
@@ -18,167 +24,154 @@ pub enum Action {
 pub struct AssemblerInstruction {
     pub label: Option<Token>,
     pub action: Action,
-    pub operand1: Option<Token>,
-    pub operand2: Option<Token>,
-    pub operand3: Option<Token>,
+    /// Operands in the order of the opcode's schema
+    pub operands: Vec<Token>,
 }
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut results = vec![];
+    /// Number of bytes the instruction encodes to. Directives take no code space
+    pub fn size(&self) -> usize {
         match &self.action {
-            Action::Opcode(Token::Op(code)) => results.push(u8::from(code)),
-            _ => panic!("Non-opcode found in opcode field"),
-        };
-        // TODO: make it nicer
-        match (&self.operand1, &self.operand2, &self.operand3) {
-            (Some(op1), Some(op2), Some(op3)) => {
-                AssemblerInstruction::extract_operand(&op1, &mut results);
-                AssemblerInstruction::extract_operand(&op2, &mut results);
-                AssemblerInstruction::extract_operand(&op3, &mut results);
+            Action::Opcode(Token::Op(code)) => {
+                1 + code
+                    .schema()
+                    .iter()
+                    .map(|(_, kind)| kind.size())
+                    .sum::<usize>()
+            }
+            _ => 0,
+        }
+    }
+
+    pub fn is_directive(&self) -> bool {
+        matches!(self.action, Action::Directive(_))
+    }
+
+    /// Bytes a directive adds to the data section
+    pub fn data(&self) -> Result<Vec<u8>, String> {
+        match (&self.action, self.operands.as_slice()) {
+            (Action::Opcode(_), _) => Ok(vec![]),
+            (Action::Directive(Token::Directive(name)), [Token::StringOperand(text)])
+                if name == "asciiz" =>
+            {
+                let mut bytes = unescape(text)?.into_bytes();
+                bytes.push(0);
+                Ok(bytes)
             }
-            (Some(op1), Some(op2), None) => {
-                AssemblerInstruction::extract_operand(&op1, &mut results);
-                AssemblerInstruction::extract_operand(&op2, &mut results);
+            (Action::Directive(Token::Directive(name)), _) if name == "asciiz" => {
+                Err("usage: .asciiz '<text>'".to_string())
             }
-            (Some(op1), None, None) => {
-                AssemblerInstruction::extract_operand(&op1, &mut results);
+            (Action::Directive(Token::Directive(name)), _) => {
+                Err(format!("unknown directive `.{}`", name))
             }
-            (None, None, None) => (),
             _ => panic!("malformed AssemblerInstruction"),
         }
-        results
     }
 
-    // TODO: add From<Token> for u8
-    fn extract_operand(t: &Token, results: &mut Vec<u8>) {
-        match t {
-            Token::Register(reg_num) => {
-                results.push(*reg_num);
-            }
-            Token::IntegerOperand(value) => {
-                let converted = *value as u16;
-                let byte1 = converted;
-                let byte2 = converted >> 8;
-                results.push(byte2 as u8);
-                results.push(byte1 as u8);
+    /// Encodes the instruction, resolving `@label` operands with `symbols`
+    pub fn to_bytes(&self, symbols: &SymbolTable) -> Result<Vec<u8>, String> {
+        let opcode = match &self.action {
+            Action::Opcode(Token::Op(code)) => *code,
+            _ => panic!("Non-opcode found in opcode field"),
+        };
+        let mut operands = vec![];
+        for token in &self.operands {
+            operands.push(AssemblerInstruction::extract_operand(token, symbols)?);
+        }
+        let instruction = Instruction::new(opcode, &operands)
+            .ok_or_else(|| format!("invalid operands for {:?}", opcode))?;
+        Ok(instruction.encode())
+    }
+
+    fn extract_operand(t: &Token, symbols: &SymbolTable) -> Result<Operand, String> {
+        Ok(match t {
+            Token::Register(reg_num) => Operand::Register(*reg_num),
+            Token::FloatRegister(reg_num) => Operand::FloatRegister(*reg_num),
+            Token::VectorRegister(reg_num) => Operand::VectorRegister(*reg_num),
+            Token::IntegerOperand(value) => Operand::Integer(
+                u16::try_from(*value)
+                    .map_err(|_| format!("`#{}` is out of 16-bit range", value))?,
+            ),
+            Token::FloatOperand(value) => Operand::Float(*value),
+            Token::LabelUsage(name) => {
+                let address = symbols
+                    .get(name)
+                    .ok_or_else(|| format!("undefined label `{}`", name))?;
+                let address = u16::try_from(*address)
+                    .map_err(|_| format!("label `{}` is out of 16-bit range", name))?;
+                Operand::Integer(address)
             }
             _ => {
                 panic!("Opcode found in operand field");
             }
-        };
+        })
     }
 }
 
-// <$REGISTER> <#VALUE>
-// LOAD $0 #100
-fn args_reg_value(
-    mut asm_instruction: AssemblerInstruction,
-    input: &str,
-) -> nom::IResult<&str, AssemblerInstruction> {
-    let parser = tuple((
-        terminated(register_parser, space1),
-        terminated(integer_operand_parser, multispace0),
-    ));
-    let (input, (register, operand)) = parser(input)?;
-    asm_instruction.operand1.replace(register);
-    asm_instruction.operand2.replace(operand);
-    Ok((input, asm_instruction))
-}
-
-// <$REGISTER> <$REGISTER> <$REGISTER>
-// ADD $0 $1 $2\n
-fn args_reg_reg_reg(
-    mut asm_instruction: AssemblerInstruction,
-    input: &str,
-) -> nom::IResult<&str, AssemblerInstruction> {
-    let parser = tuple((
-        terminated(register_parser, space1),
-        terminated(register_parser, space1),
-        terminated(register_parser, multispace0),
-    ));
-    let (input, (reg1, reg2, reg3)) = parser(input)?;
-    asm_instruction.operand1.replace(reg1);
-    asm_instruction.operand2.replace(reg2);
-    asm_instruction.operand3.replace(reg3);
-    Ok((input, asm_instruction))
-}
-
-// <$REGISTER> <$REGISTER>
-// EQ $0 $1\n
-fn args_reg_reg(
-    mut asm_instruction: AssemblerInstruction,
-    input: &str,
-) -> nom::IResult<&str, AssemblerInstruction> {
-    let parser = tuple((
-        terminated(register_parser, space1),
-        terminated(register_parser, multispace0),
-    ));
-    let (input, (reg1, reg2)) = parser(input)?;
-    asm_instruction.operand1.replace(reg1);
-    asm_instruction.operand2.replace(reg2);
-    Ok((input, asm_instruction))
-}
-
-// <OPCODE> <$REGISTER>
-// JMP $0 \n
-fn args_reg(
-    mut asm_instruction: AssemblerInstruction,
-    input: &str,
-) -> nom::IResult<&str, AssemblerInstruction> {
-    let parser = terminated(register_parser, multispace0);
-    let (input, reg1) = parser(input)?;
-    asm_instruction.operand1.replace(reg1);
-    Ok((input, asm_instruction))
+// \n, \t, \0 and \\ escapes of string operands
+fn unescape(text: &str) -> Result<String, String> {
+    let mut result = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('0') => result.push('\0'),
+            Some('\\') => result.push('\\'),
+            Some(other) => return Err(format!("unknown escape `\\{}`", other)),
+            None => return Err("unterminated escape".to_string()),
+        }
+    }
+    Ok(result)
 }
 
-// // <OPCODE>
-// // HLT
-fn args_none(
-    asm_instruction: AssemblerInstruction,
-    input: &str,
-) -> nom::IResult<&str, AssemblerInstruction> {
-    Ok((input, asm_instruction))
+// one operand of the given kind, integers may also be `@label`s
+fn operand(kind: OperandKind, input: &str) -> nom::IResult<&str, Token> {
+    match kind {
+        OperandKind::Register => register_parser(input),
+        OperandKind::FloatRegister => float_register_parser(input),
+        OperandKind::VectorRegister => vector_register_parser(input),
+        OperandKind::Integer => alt((integer_operand_parser, label_usage_parser))(input),
+        OperandKind::Float => float_operand_parser(input),
+    }
 }
 
+// operands following the opcode's schema, separated by spaces
+// LOAD $0 #100
+// FTOI $f0 $1
 pub fn instruction(input: &str) -> nom::IResult<&str, AssemblerInstruction> {
     let (input, label) = terminated(opt(label_declaration_parser), multispace0)(input)?;
-    let (input, opcode) = terminated(opcode_parser, multispace0)(input)?;
-    let parser = match &opcode {
-        Token::Op(code) => match code {
-            Opcode::HLT => args_none,
-            Opcode::LOAD => args_reg_value,
-            Opcode::ADD => args_reg_reg_reg,
-            Opcode::SUB => args_reg_reg_reg,
-            Opcode::MUL => args_reg_reg_reg,
-            Opcode::DIV => args_reg_reg_reg,
-            Opcode::JMP => args_reg,
-            Opcode::JMPF => args_reg,
-            Opcode::JMPB => args_reg,
-            Opcode::JEQ => args_reg,
-            Opcode::EQ => args_reg_reg,
-            Opcode::NEQ => args_reg_reg,
-            Opcode::GT => args_reg_reg,
-            Opcode::LT => args_reg_reg,
-            Opcode::GTQ => args_reg_reg,
-            Opcode::LTQ => args_reg_reg,
-            Opcode::IGL(_) => args_none,
-        },
+    let (mut input, opcode) = terminated(opcode_parser, multispace0)(input)?;
+    let schema = match &opcode {
+        Token::Op(code) => code.schema(),
         _ => panic!("non Opcode output from opcode parser"),
     };
+    let mut operands = vec![];
+    for (index, (_, kind)) in schema.iter().enumerate() {
+        let (rest, token) = operand(*kind, input)?;
+        let (rest, _) = match index + 1 == schema.len() {
+            true => multispace0(rest)?,
+            false => space1(rest)?,
+        };
+        operands.push(token);
+        input = rest;
+    }
     let asm_instruction = AssemblerInstruction {
         label,
         action: Action::Opcode(opcode),
-        operand1: None,
-        operand2: None,
-        operand3: None,
+        operands,
     };
-    parser(asm_instruction, input)
+    Ok((input, asm_instruction))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::assembler::directive_parsers::directive;
     use crate::instructions::Opcode;
 
     #[test]
@@ -191,9 +184,7 @@ mod tests {
                 AssemblerInstruction {
                     label: None,
                     action: Action::Opcode(Token::Op(Opcode::LOAD)),
-                    operand1: Some(Token::Register(0)),
-                    operand2: Some(Token::IntegerOperand(100)),
-                    operand3: None
+                    operands: vec![Token::Register(0), Token::IntegerOperand(100)],
                 }
             ))
         );
@@ -209,9 +200,7 @@ mod tests {
                 AssemblerInstruction {
                     label: Some(Token::LabelDeclaration("add".to_string())),
                     action: Action::Opcode(Token::Op(Opcode::ADD)),
-                    operand1: Some(Token::Register(0)),
-                    operand2: Some(Token::Register(1)),
-                    operand3: Some(Token::Register(2)),
+                    operands: vec![Token::Register(0), Token::Register(1), Token::Register(2)],
                 }
             ))
         );
@@ -227,9 +216,7 @@ mod tests {
                 AssemblerInstruction {
                     label: None,
                     action: Action::Opcode(Token::Op(Opcode::HLT)),
-                    operand1: None,
-                    operand2: None,
-                    operand3: None,
+                    operands: vec![],
                 }
             ))
         );
@@ -245,9 +232,7 @@ mod tests {
                 AssemblerInstruction {
                     label: None,
                     action: Action::Opcode(Token::Op(Opcode::LOAD)),
-                    operand1: Some(Token::Register(0)),
-                    operand2: Some(Token::IntegerOperand(100)),
-                    operand3: None
+                    operands: vec![Token::Register(0), Token::IntegerOperand(100)],
                 }
             ))
         );
@@ -271,11 +256,46 @@ mod tests {
                 AssemblerInstruction {
                     label: Some(Token::LabelDeclaration("jump".to_string())),
                     action: Action::Opcode(Token::Op(Opcode::JMP)),
-                    operand1: Some(Token::Register(9)),
-                    operand2: None,
-                    operand3: None
+                    operands: vec![Token::Register(9)],
                 }
             ))
         );
     }
+
+    #[test]
+    fn test_label_operand() {
+        let (_, load) = instruction("load $3 @loop\n").unwrap();
+        assert_eq!(load.operands[1], Token::LabelUsage("loop".to_string()));
+        assert_eq!(load.size(), 4);
+        let (_, loadf) = instruction("loadf $f1 #2.5\n").unwrap();
+        assert_eq!(loadf.size(), 10);
+        let (_, vst) = instruction("vst $v1 $2\n").unwrap();
+        assert_eq!(vst.size(), 3);
+        let mut symbols = SymbolTable::new();
+        assert_eq!(
+            load.to_bytes(&symbols),
+            Err("undefined label `loop`".to_string())
+        );
+        symbols.insert("loop".to_string(), 0x1234);
+        assert_eq!(load.to_bytes(&symbols), Ok(vec![1, 3, 0x12, 0x34]));
+        let (_, load) = instruction("load $0 #70000\n").unwrap();
+        assert_eq!(
+            load.to_bytes(&symbols),
+            Err("`#70000` is out of 16-bit range".to_string())
+        );
+    }
+
+    #[test]
+    fn test_directive_data() {
+        let (_, asciiz) = directive("hello: .asciiz 'Hi\\n'").unwrap();
+        assert_eq!(asciiz.size(), 0);
+        assert_eq!(asciiz.data(), Ok(b"Hi\n\0".to_vec()));
+        let (_, unknown) = directive(".word #1").unwrap();
+        assert_eq!(unknown.data(), Err("unknown directive `.word`".to_string()));
+        let (_, bad) = directive(".asciiz 'a\\q'").unwrap();
+        assert_eq!(bad.data(), Err("unknown escape `\\q`".to_string()));
+        let (_, extra) = directive(".asciiz 'a' 'b' 'c' 'd'").unwrap();
+        assert_eq!(extra.operands.len(), 4);
+        assert_eq!(extra.data(), Err("usage: .asciiz '<text>'".to_string()));
+    }
 }
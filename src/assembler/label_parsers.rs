@@ -17,6 +17,7 @@ pub fn label_usage_parser(input: &str) -> nom::IResult<&str, Token> {
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -1,10 +1,16 @@
-pub mod directive_parsers;
-pub mod instruction_parsers;
-pub mod label_parsers;
-pub mod opcode_parsers;
-pub mod operand_parsers;
-pub mod program_parsers;
+pub(crate) mod directive_parsers;
+pub(crate) mod instruction_parsers;
+pub(crate) mod label_parsers;
+pub(crate) mod opcode_parsers;
+pub(crate) mod operand_parsers;
+pub(crate) mod program_parsers;
 
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+
+use crate::image::{Image, MAGIC};
 use crate::instructions::Opcode;
 
 #[derive(Debug, PartialEq)]
@@ -14,9 +20,219 @@ pub enum Token {
     Op(Opcode),
     // operands
     IntegerOperand(i32),
+    FloatOperand(f64),
     StringOperand(String),
     Register(u8),
+    FloatRegister(u8),
+    VectorRegister(u8),
     // labels
     LabelDeclaration(String),
     LabelUsage(String),
 }
+
+/// Addresses of labels, by name
+pub type SymbolTable = BTreeMap<String, usize>;
+
+/// Problem found in a source file, displayed as `file:line: message`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(file: &str, line: usize, message: impl Into<String>) -> Self {
+        Diagnostic {
+            file: file.to_string(),
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+/// Every problem found while assembling, one per line when displayed
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl From<Diagnostic> for Diagnostics {
+    fn from(diagnostic: Diagnostic) -> Self {
+        Diagnostics(vec![diagnostic])
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, diagnostic) in self.0.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Diagnostics {}
+
+/// Turns source text into bytecode images.
+///
+/// ```
+/// let image = toyvm::Assembler::new().assemble("load $0 #42\nhlt\n").unwrap();
+/// assert_eq!(image.code, vec![1, 0, 0, 42, 0]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Assembler {
+    file: String,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Assembler {
+            file: "<input>".to_string(),
+        }
+    }
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the file name reported in diagnostics and debug info
+    pub fn file(mut self, file: &str) -> Self {
+        self.file = file.to_string();
+        self
+    }
+
+    pub fn assemble(&self, source: &str) -> Result<Image, Diagnostics> {
+        assemble(&self.file, source)
+    }
+}
+
+/// Assembles a whole source file into a bytecode image with debug info
+pub fn assemble(file: &str, source: &str) -> Result<Image, Diagnostics> {
+    assemble_at(file, source, 0, 0, &SymbolTable::new())
+}
+
+/// Assembles a source file to be placed at `base` after code declaring the `known` labels,
+/// with its data at memory address `data_base`
+pub fn assemble_at(
+    file: &str,
+    source: &str,
+    base: usize,
+    data_base: usize,
+    known: &SymbolTable,
+) -> Result<Image, Diagnostics> {
+    let (rest, program) = program_parsers::program_parser(source).map_err(|err| {
+        let (rest, message) = match err {
+            // only the opcode parser fails for good, on a word that isn't a mnemonic
+            nom::Err::Failure((rest, _)) => (rest, unknown_opcode(rest)),
+            nom::Err::Error((rest, _)) => (rest, "unable to parse program".to_string()),
+            nom::Err::Incomplete(_) => (source, "unable to parse program".to_string()),
+        };
+        Diagnostic::new(file, line_of(source, rest), message)
+    })?;
+    if !rest.is_empty() {
+        let line = line_of(source, rest);
+        return Err(Diagnostic::new(file, line, "unable to parse instruction").into());
+    }
+    program.to_image_at(file, base, data_base, known)
+}
+
+// line of `source` its suffix `rest` starts on
+pub(crate) fn line_of(source: &str, rest: &str) -> usize {
+    source[..source.len() - rest.len()].matches('\n').count() + 1
+}
+
+// message for the word at the start of `rest` the opcode parser rejected
+pub(crate) fn unknown_opcode(rest: &str) -> String {
+    let mnemonic = rest.split_whitespace().next().unwrap_or(rest);
+    format!("unknown opcode `{}`", mnemonic)
+}
+
+/// Reads a bytecode image or assembles a source file
+pub fn load(path: &str) -> Result<Image, String> {
+    let bytes = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+    if bytes.starts_with(MAGIC) {
+        return Image::from_bytes(&bytes).map_err(|err| format!("{}: {:?}", path, err));
+    }
+    let source = String::from_utf8(bytes).map_err(|err| format!("{}: {}", path, err))?;
+    assemble(path, &source).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assemble() {
+        let image = assemble("test.s", "load $0 #1\nstart: hlt\n").unwrap();
+        assert_eq!(image.code, vec![1, 0, 0, 1, 0]);
+        let debug_info = image.debug_info.unwrap();
+        assert_eq!(debug_info.files, vec!["test.s"]);
+        assert_eq!(debug_info.address_of("start"), Some(4));
+    }
+
+    #[test]
+    fn test_assemble_labels() {
+        let image = assemble("test.s", "load $1 @end\nstart: jmp $1\nend: hlt\n").unwrap();
+        assert_eq!(image.code, vec![1, 1, 0, 6, 6, 1, 0]);
+        let errors = assemble("test.s", "load $1 @a\na: hlt\na: hlt\nload $1 @b\n").unwrap_err();
+        assert_eq!(
+            errors.to_string(),
+            "test.s:3: label `a` is already defined\ntest.s:4: undefined label `b`"
+        );
+    }
+
+    #[test]
+    fn test_assemble_error_line() {
+        let result = assemble("test.s", "load $0 #1\nhlt\nload $0 1\n");
+        assert_eq!(
+            result,
+            Err(Diagnostic::new("test.s", 3, "unable to parse instruction").into())
+        );
+        let result = assemble("test.s", "\n\nload $0 1\nhlt\n");
+        assert_eq!(
+            result,
+            Err(Diagnostic::new("test.s", 3, "unable to parse program").into())
+        );
+        let result = assemble("test.s", "hlt\nloadf $f32 #1.5\n");
+        assert_eq!(
+            result,
+            Err(Diagnostic::new("test.s", 2, "unable to parse instruction").into())
+        );
+        let result = assemble("test.s", "hlt\nhlt\nvsum $v16 $0\n");
+        assert_eq!(
+            result,
+            Err(Diagnostic::new("test.s", 3, "unable to parse instruction").into())
+        );
+        let result = assemble("test.s", "hlt\nload $0 #65536\n");
+        assert_eq!(
+            result,
+            Err(Diagnostic::new("test.s", 2, "`#65536` is out of 16-bit range").into())
+        );
+        let result = assemble("test.s", "load $0 #1\nhtl\n");
+        assert_eq!(
+            result,
+            Err(Diagnostic::new("test.s", 2, "unknown opcode `htl`").into())
+        );
+        let result = assemble("test.s", "start: rte $0\n");
+        assert_eq!(
+            result,
+            Err(Diagnostic::new("test.s", 1, "unknown opcode `rte`").into())
+        );
+        assert!(assemble("test.s", "igl\n").is_ok());
+        let result = assemble("test.s", "load $32 #1\n");
+        assert_eq!(
+            result,
+            Err(Diagnostic::new("test.s", 1, "unable to parse program").into())
+        );
+    }
+}
@@ -1,14 +1,23 @@
 use nom::character::complete::alpha1;
+use nom::error::ErrorKind;
 use nom::IResult;
 
 use crate::assembler::Token;
 use crate::instructions::Opcode;
 
+// an unknown mnemonic is a failure pointing at it, only `igl` assembles to IGL
 pub fn opcode_parser(input: &str) -> IResult<&str, Token> {
-    alpha1(input).map(|(rest, opcode)| (rest, Token::Op(Opcode::from(opcode))))
+    let (rest, mnemonic) = alpha1(input)?;
+    match Opcode::from(mnemonic) {
+        Opcode::IGL(_) if !mnemonic.eq_ignore_ascii_case("igl") => {
+            Err(nom::Err::Failure((input, ErrorKind::Tag)))
+        }
+        opcode => Ok((rest, Token::Op(opcode))),
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -33,8 +42,8 @@ mod tests {
     #[test]
     fn test_opcode_parser_invalid_opcode() {
         let result = opcode_parser("aold");
-        assert_eq!(result.is_ok(), true);
-        let (rest, token) = result.unwrap();
+        assert_eq!(result, Err(nom::Err::Failure(("aold", ErrorKind::Tag))));
+        let (rest, token) = opcode_parser("IGL").unwrap();
         assert_eq!(token, Token::Op(Opcode::IGL(0xFF)));
         assert_eq!(rest, "");
     }
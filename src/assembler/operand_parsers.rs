@@ -1,9 +1,11 @@
 use nom::branch::alt;
 use nom::bytes::complete::{tag, take_until};
 use nom::character::complete::digit1;
+use nom::number::complete::recognize_float;
 use nom::sequence::{preceded, tuple};
 
 use crate::assembler::Token;
+use crate::vm::{FLOAT_REGISTER_COUNT, REGISTER_COUNT, VECTOR_REGISTER_COUNT};
 
 pub fn integer_operand_parser(input: &str) -> nom::IResult<&str, Token> {
     preceded(tag("#"), digit1)(input).and_then(|(rest, value)| match value.parse::<i32>() {
@@ -14,8 +16,34 @@ pub fn integer_operand_parser(input: &str) -> nom::IResult<&str, Token> {
 
 pub fn register_parser(input: &str) -> nom::IResult<&str, Token> {
     preceded(tag("$"), digit1)(input).and_then(|(rest, value)| match value.parse::<u8>() {
-        Ok(num) => Ok((rest, Token::Register(num))),
-        Err(_) => Err(nom::Err::Error((input, nom::error::ErrorKind::Digit))),
+        Ok(num) if (num as usize) < REGISTER_COUNT => Ok((rest, Token::Register(num))),
+        _ => Err(nom::Err::Error((input, nom::error::ErrorKind::Digit))),
+    })
+}
+
+// #1.5, #-2e3
+pub fn float_operand_parser(input: &str) -> nom::IResult<&str, Token> {
+    preceded(tag("#"), recognize_float)(input).and_then(|(rest, value)| {
+        match value.parse::<f64>() {
+            Ok(num) => Ok((rest, Token::FloatOperand(num))),
+            Err(_) => Err(nom::Err::Error((input, nom::error::ErrorKind::Float))),
+        }
+    })
+}
+
+// $f3, up to the last float register
+pub fn float_register_parser(input: &str) -> nom::IResult<&str, Token> {
+    preceded(tag("$f"), digit1)(input).and_then(|(rest, value)| match value.parse::<u8>() {
+        Ok(num) if (num as usize) < FLOAT_REGISTER_COUNT => Ok((rest, Token::FloatRegister(num))),
+        _ => Err(nom::Err::Error((input, nom::error::ErrorKind::Digit))),
+    })
+}
+
+// $v3, up to the last vector register
+pub fn vector_register_parser(input: &str) -> nom::IResult<&str, Token> {
+    preceded(tag("$v"), digit1)(input).and_then(|(rest, value)| match value.parse::<u8>() {
+        Ok(num) if (num as usize) < VECTOR_REGISTER_COUNT => Ok((rest, Token::VectorRegister(num))),
+        _ => Err(nom::Err::Error((input, nom::error::ErrorKind::Digit))),
     })
 }
 
@@ -28,11 +56,14 @@ pub fn any_operand_parser(input: &str) -> nom::IResult<&str, Token> {
     alt((
         integer_operand_parser,
         register_parser,
+        float_register_parser,
+        vector_register_parser,
         string_operand_parser,
     ))(input)
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
@@ -44,6 +75,8 @@ mod tests {
         assert_eq!(result.is_ok(), false);
         let result = register_parser("$a");
         assert_eq!(result.is_ok(), false);
+        assert!(register_parser("$31").is_ok());
+        assert!(register_parser("$32").is_err());
     }
 
     #[test]
@@ -60,6 +93,38 @@ mod tests {
         assert_eq!(result.is_ok(), false);
     }
 
+    #[test]
+    fn test_parse_float_operands() {
+        assert_eq!(
+            float_register_parser("$f3"),
+            Ok(("", Token::FloatRegister(3)))
+        );
+        assert!(float_register_parser("$3").is_err());
+        assert!(float_register_parser("$f31").is_ok());
+        assert!(float_register_parser("$f32").is_err());
+        assert!(register_parser("$f3").is_err());
+        assert_eq!(
+            float_operand_parser("#-1.5e2"),
+            Ok(("", Token::FloatOperand(-150.0)))
+        );
+        assert_eq!(
+            float_operand_parser("#2"),
+            Ok(("", Token::FloatOperand(2.0)))
+        );
+        assert!(float_operand_parser("1.5").is_err());
+    }
+
+    #[test]
+    fn test_parse_vector_register() {
+        assert_eq!(
+            vector_register_parser("$v2"),
+            Ok(("", Token::VectorRegister(2)))
+        );
+        assert!(vector_register_parser("$f2").is_err());
+        assert!(vector_register_parser("$v15").is_ok());
+        assert!(vector_register_parser("$v16").is_err());
+    }
+
     #[test]
     fn test_parse_string_operand() {
         // Test a valid integer operand
@@ -1,28 +1,157 @@
-use nom::multi::many1;
+use nom::branch::alt;
+use nom::character::complete::multispace0;
 
+use crate::assembler::directive_parsers::directive;
 use crate::assembler::instruction_parsers::{instruction, AssemblerInstruction};
+use crate::assembler::{Diagnostic, Diagnostics, SymbolTable, Token};
+use crate::debug_info::{DebugInfo, LineEntry, Symbol};
+use crate::image::Image;
 
 #[derive(Debug, PartialEq)]
 pub struct Program {
     instructions: Vec<AssemblerInstruction>,
+    /// Source line of every instruction
+    lines: Vec<usize>,
 }
 
 impl Program {
-    pub fn to_bytes(&self) -> Vec<u8> {
-        self.instructions
-            .iter()
-            .map(|instruction| instruction.to_bytes())
-            .flatten()
-            .collect()
+    /// Encodes the program placed at address `base`. Labels are resolved
+    /// among its own declarations and the `known` ones
+    #[cfg(test)]
+    pub fn to_bytes(
+        &self,
+        file: &str,
+        base: usize,
+        known: &SymbolTable,
+    ) -> Result<Vec<u8>, Diagnostics> {
+        self.encode(file, base, 0, known).map(|(code, _)| code)
+    }
+
+    // (code, data). Labels of directives are memory addresses of the data, placed at `data_base`
+    fn encode(
+        &self,
+        file: &str,
+        base: usize,
+        data_base: usize,
+        known: &SymbolTable,
+    ) -> Result<(Vec<u8>, Vec<u8>), Diagnostics> {
+        let mut errors = vec![];
+        let mut symbols = known.clone();
+        let mut data = vec![];
+        let mut pc = base;
+        for (instruction, &line) in self.instructions.iter().zip(&self.lines) {
+            let address = if instruction.is_directive() {
+                data_base + data.len()
+            } else {
+                pc
+            };
+            match instruction.data() {
+                Ok(bytes) => data.extend(bytes),
+                Err(message) => errors.push(Diagnostic::new(file, line, message)),
+            }
+            if let Some(Token::LabelDeclaration(name)) = &instruction.label {
+                if symbols.insert(name.clone(), address).is_some() {
+                    let message = format!("label `{}` is already defined", name);
+                    errors.push(Diagnostic::new(file, line, message));
+                }
+            }
+            pc += instruction.size();
+        }
+        let mut code = vec![];
+        for (instruction, &line) in self.instructions.iter().zip(&self.lines) {
+            if instruction.is_directive() {
+                continue;
+            }
+            match instruction.to_bytes(&symbols) {
+                Ok(encoded) => code.extend(encoded),
+                Err(message) => errors.push(Diagnostic::new(file, line, message)),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(Diagnostics(errors));
+        }
+        Ok((code, data))
+    }
+
+    /// Numbers source lines starting after `offset`, for code taken from the middle of a file
+    #[cfg(feature = "cli")]
+    pub fn offset_lines(&mut self, offset: usize) {
+        for line in self.lines.iter_mut() {
+            *line += offset;
+        }
+    }
+
+    pub fn debug_info(&self, file: &str) -> DebugInfo {
+        let mut debug_info = DebugInfo::new(file);
+        let mut pc = 0;
+        for (instruction, &line) in self.instructions.iter().zip(&self.lines) {
+            if instruction.is_directive() {
+                continue;
+            }
+            if let Some(Token::LabelDeclaration(name)) = &instruction.label {
+                debug_info.symbols.push(Symbol {
+                    name: name.clone(),
+                    offset: pc,
+                });
+            }
+            let end = pc + instruction.size();
+            debug_info.lines.push(LineEntry {
+                start: pc,
+                end,
+                line,
+                file: 0,
+            });
+            pc = end;
+        }
+        debug_info
+    }
+
+    /// Image of the program to be appended at `base` after code declaring `known` labels,
+    /// with its data at memory address `data_base`
+    pub fn to_image_at(
+        &self,
+        file: &str,
+        base: usize,
+        data_base: usize,
+        known: &SymbolTable,
+    ) -> Result<Image, Diagnostics> {
+        let (code, data) = self.encode(file, base, data_base, known)?;
+        Ok(Image {
+            code,
+            data,
+            debug_info: Some(self.debug_info(file)),
+        })
     }
 }
 
 pub fn program_parser(input: &str) -> nom::IResult<&str, Program> {
-    let (input, instructions) = many1(instruction)(input)?;
-    Ok((input, Program { instructions }))
+    let (mut rest, _) = multispace0(input)?;
+    let mut program = Program {
+        instructions: vec![],
+        lines: vec![],
+    };
+    loop {
+        match alt((instruction, directive))(rest) {
+            Ok((tail, asm_instruction)) => {
+                let offset = input.len() - rest.len();
+                program
+                    .lines
+                    .push(input[..offset].matches('\n').count() + 1);
+                program.instructions.push(asm_instruction);
+                rest = tail;
+            }
+            Err(nom::Err::Error(_)) if !program.instructions.is_empty() => {
+                break Ok((rest, program));
+            }
+            // point at the start of the instruction that failed
+            Err(nom::Err::Error((_, kind))) => break Err(nom::Err::Error((rest, kind))),
+            Err(err) => break Err(err),
+        }
+    }
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn test_parse_program() {
     let result = program_parser("load $0 #100\n");
     assert_eq!(result.is_ok(), true);
@@ -32,11 +161,66 @@ fn test_parse_program() {
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn test_program_to_bytes() {
     let result = program_parser("load $0 #100\n");
     assert_eq!(result.is_ok(), true);
     let (_, program) = result.unwrap();
-    let bytecode = program.to_bytes();
+    let bytecode = program.to_bytes("test.s", 0, &SymbolTable::new()).unwrap();
     assert_eq!(bytecode.len(), 4);
     println!("{:?}", bytecode);
 }
+
+#[test]
+fn test_program_debug_info() {
+    let (_, program) = program_parser("\nload $0 #100\n\nloop: jmp $0\nhlt\n").unwrap();
+    assert_eq!(program.lines, vec![2, 4, 5]);
+    let debug_info = program.debug_info("loop.s");
+    assert_eq!(
+        debug_info.lines,
+        vec![
+            LineEntry {
+                start: 0,
+                end: 4,
+                line: 2,
+                file: 0,
+            },
+            LineEntry {
+                start: 4,
+                end: 6,
+                line: 4,
+                file: 0,
+            },
+            LineEntry {
+                start: 6,
+                end: 7,
+                line: 5,
+                file: 0,
+            },
+        ]
+    );
+    assert_eq!(debug_info.address_of("loop"), Some(4));
+    assert_eq!(
+        debug_info.location(6),
+        Some("loop.s:5 (in `loop`)".to_string())
+    );
+}
+
+#[test]
+fn test_program_data() {
+    let source = "load $1 @greeting\nprts $1\nhlt\ngreeting: .asciiz 'Hi'\nbye: .asciiz 'Bye'\n";
+    let (rest, program) = program_parser(source).unwrap();
+    assert_eq!(rest, "");
+    let image = program
+        .to_image_at("data.s", 0, 0, &SymbolTable::new())
+        .unwrap();
+    assert_eq!(image.data, b"Hi\0Bye\0".to_vec());
+    assert_eq!(&image.code[..4], &[1, 1, 0, 0]);
+    let image = program
+        .to_image_at("data.s", 0, 0x20, &SymbolTable::new())
+        .unwrap();
+    assert_eq!(&image.code[..4], &[1, 1, 0, 0x20]);
+    let debug_info = image.debug_info.unwrap();
+    assert_eq!(debug_info.lines.len(), 3);
+    assert_eq!(debug_info.address_of("greeting"), None);
+}
@@ -0,0 +1,211 @@
+use crate::image::{put_bytes, put_u32, ImageError, Reader};
+
+/// Bytecode range [start, end) produced by a single source line
+#[derive(Debug, PartialEq, Clone)]
+pub struct LineEntry {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    /// Index in `DebugInfo::files`
+    pub file: usize,
+}
+
+/// Label declared in the source and the bytecode offset it points to
+#[derive(Debug, PartialEq, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub offset: usize,
+}
+
+/// Line table and symbols emitted by the assembler
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct DebugInfo {
+    /// Source files the program was assembled from
+    pub files: Vec<String>,
+    /// Sorted by `start`
+    pub lines: Vec<LineEntry>,
+    /// Sorted by `offset`
+    pub symbols: Vec<Symbol>,
+}
+
+impl DebugInfo {
+    pub fn new(file: &str) -> Self {
+        DebugInfo {
+            files: vec![file.to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn entry(&self, pc: usize) -> Option<&LineEntry> {
+        self.lines
+            .iter()
+            .find(|entry| entry.start <= pc && pc < entry.end)
+    }
+
+    pub fn line(&self, pc: usize) -> Option<usize> {
+        self.entry(pc).map(|entry| entry.line)
+    }
+
+    /// Source file `pc` was assembled from
+    pub fn file(&self, pc: usize) -> Option<&str> {
+        self.entry(pc)
+            .and_then(|entry| self.files.get(entry.file))
+            .map(String::as_str)
+    }
+
+    /// Closest label declared at or before `pc`
+    pub fn symbol(&self, pc: usize) -> Option<&Symbol> {
+        self.symbols.iter().rev().find(|symbol| symbol.offset <= pc)
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<usize> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| symbol.offset)
+    }
+
+    /// Formats `pc` as `file:line (in `label`)`, if the line table covers it
+    pub fn location(&self, pc: usize) -> Option<String> {
+        let line = self.line(pc)?;
+        let file = self.file(pc).unwrap_or_default();
+        Some(match self.symbol(pc) {
+            Some(symbol) => format!("{}:{} (in `{}`)", file, line, symbol.name),
+            None => format!("{}:{}", file, line),
+        })
+    }
+
+    /// Appends debug info of a program placed at `pc_offset`,
+    /// which source lines are numbered starting after `line_offset`
+    pub fn append(&mut self, other: &DebugInfo, pc_offset: usize, line_offset: usize) {
+        let files: Vec<usize> = other.files.iter().map(|file| self.add_file(file)).collect();
+        self.lines.extend(other.lines.iter().map(|entry| LineEntry {
+            start: entry.start + pc_offset,
+            end: entry.end + pc_offset,
+            line: entry.line + line_offset,
+            file: files[entry.file],
+        }));
+        self.symbols
+            .extend(other.symbols.iter().map(|symbol| Symbol {
+                name: symbol.name.clone(),
+                offset: symbol.offset + pc_offset,
+            }));
+    }
+
+    fn add_file(&mut self, file: &str) -> usize {
+        match self.files.iter().position(|known| known == file) {
+            Some(index) => index,
+            None => {
+                self.files.push(file.to_string());
+                self.files.len() - 1
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        put_u32(&mut out, self.files.len() as u32);
+        for file in &self.files {
+            put_bytes(&mut out, file.as_bytes());
+        }
+        put_u32(&mut out, self.lines.len() as u32);
+        for entry in &self.lines {
+            put_u32(&mut out, entry.start as u32);
+            put_u32(&mut out, entry.end as u32);
+            put_u32(&mut out, entry.line as u32);
+            put_u32(&mut out, entry.file as u32);
+        }
+        put_u32(&mut out, self.symbols.len() as u32);
+        for symbol in &self.symbols {
+            put_bytes(&mut out, symbol.name.as_bytes());
+            put_u32(&mut out, symbol.offset as u32);
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        let mut reader = Reader::new(bytes);
+        let mut debug_info = DebugInfo::default();
+        for _ in 0..reader.u32()? {
+            debug_info.files.push(reader.string()?);
+        }
+        for _ in 0..reader.u32()? {
+            debug_info.lines.push(LineEntry {
+                start: reader.u32()? as usize,
+                end: reader.u32()? as usize,
+                line: reader.u32()? as usize,
+                file: reader.u32()? as usize,
+            });
+        }
+        for _ in 0..reader.u32()? {
+            debug_info.symbols.push(Symbol {
+                name: reader.string()?,
+                offset: reader.u32()? as usize,
+            });
+        }
+        Ok(debug_info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loop_debug_info() -> DebugInfo {
+        DebugInfo {
+            files: vec!["loop.s".to_string()],
+            lines: vec![
+                LineEntry {
+                    start: 0,
+                    end: 4,
+                    line: 1,
+                    file: 0,
+                },
+                LineEntry {
+                    start: 4,
+                    end: 7,
+                    line: 3,
+                    file: 0,
+                },
+            ],
+            symbols: vec![Symbol {
+                name: "loop".to_string(),
+                offset: 4,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_location() {
+        let debug_info = loop_debug_info();
+        assert_eq!(debug_info.location(0), Some("loop.s:1".to_string()));
+        assert_eq!(
+            debug_info.location(5),
+            Some("loop.s:3 (in `loop`)".to_string())
+        );
+        assert_eq!(debug_info.location(7), None);
+        assert_eq!(debug_info.address_of("loop"), Some(4));
+        assert_eq!(debug_info.address_of("nope"), None);
+    }
+
+    #[test]
+    fn test_append() {
+        let mut debug_info = DebugInfo::new("<repl>");
+        debug_info.append(&DebugInfo::new("<repl>"), 0, 0);
+        debug_info.append(&loop_debug_info(), 10, 1);
+        assert_eq!(debug_info.files, vec!["<repl>", "loop.s"]);
+        assert_eq!(debug_info.line(10), Some(2));
+        assert_eq!(debug_info.line(14), Some(4));
+        assert_eq!(debug_info.file(14), Some("loop.s"));
+        assert_eq!(debug_info.address_of("loop"), Some(14));
+    }
+
+    #[test]
+    fn test_debug_info_roundtrip() {
+        let debug_info = loop_debug_info();
+        assert_eq!(
+            DebugInfo::from_bytes(&debug_info.to_bytes()),
+            Ok(debug_info)
+        );
+    }
+}
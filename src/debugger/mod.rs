@@ -0,0 +1,568 @@
+use std::collections::BTreeSet;
+use std::io;
+use std::io::{BufRead, Write};
+
+use crate::disassembler::{disassemble, disassemble_one};
+use crate::vm::{Register, Step, WatchHit, WatchKind, VM};
+
+/// Number of instructions listed before and after pc by `disas`
+const DISASSEMBLY_CONTEXT: usize = 3;
+
+const HELP: &str = "\
+break <addr|label>   set a breakpoint (alias: b)
+delete <addr|label>  remove a breakpoint (alias: d)
+breakpoints          list breakpoints
+watch $N [change]    stop when $N is written, or only when its value changes
+watch mem <addr> <len> [change]
+                     the same for the bytes from addr
+unwatch <$N|mem <addr>>
+                     remove a watchpoint
+step                 execute one instruction (alias: s)
+next                 step over the current instruction (alias: n)
+continue             run until a breakpoint or HLT (alias: c)
+step-back            undo the last executed instruction (alias: sb)
+reverse-continue     undo instructions until a breakpoint (alias: rc)
+registers            print all registers (alias: regs)
+print <$N|pc|flags|remainder>
+                     print a piece of VM state (alias: p)
+disas                disassemble around pc
+quit                 leave the debugger (alias: q)";
+
+/// Reason the debugger handed control back to the user
+#[derive(Debug, PartialEq)]
+pub enum Stop {
+    /// A single instruction was executed
+    Stepped,
+    Breakpoint(usize),
+    Watchpoint(WatchHit),
+    Halted,
+    Error(u8),
+    /// Nothing left to undo
+    HistoryStart,
+}
+
+pub struct Debugger {
+    vm: VM,
+    breakpoints: BTreeSet<usize>,
+    /// Set once the program hits HLT or fails
+    halted: bool,
+    /// pc where control was last handed back to the user
+    stopped_at: Option<usize>,
+}
+
+impl Debugger {
+    pub fn new(mut vm: VM) -> Self {
+        vm.set_recording(true);
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            halted: false,
+            stopped_at: None,
+        }
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn add_breakpoint(&mut self, target: &str) -> Result<usize, String> {
+        let address = self.resolve(target)?;
+        self.breakpoints.insert(address);
+        Ok(address)
+    }
+
+    pub fn remove_breakpoint(&mut self, target: &str) -> Result<usize, String> {
+        let address = self.resolve(target)?;
+        if self.breakpoints.remove(&address) {
+            Ok(address)
+        } else {
+            Err(format!("no breakpoint at {}", address))
+        }
+    }
+
+    // <addr> is either decimal, 0x prefixed hex or a label name
+    fn resolve(&self, target: &str) -> Result<usize, String> {
+        parse_address(target)
+            .or_else(|| {
+                self.vm
+                    .debug_info
+                    .as_ref()
+                    .and_then(|debug_info| debug_info.address_of(target))
+            })
+            .ok_or_else(|| format!("unknown address or label `{}`", target))
+    }
+
+    pub fn step(&mut self) -> Stop {
+        if self.halted {
+            return Stop::Halted;
+        }
+        let result = self.vm.run_once();
+        self.stopped_at = Some(self.vm.pc());
+        match result {
+            Ok(Step::Continue) | Ok(Step::BudgetExhausted) => Stop::Stepped,
+            Ok(Step::Watchpoint(hit)) => Stop::Watchpoint(hit),
+            Ok(Step::Done) => {
+                self.halted = true;
+                Stop::Halted
+            }
+            Err(code) => {
+                self.halted = true;
+                Stop::Error(code)
+            }
+        }
+    }
+
+    /// Runs until the instruction following the current one is reached,
+    /// so a jump back to the start of a loop executes the whole loop
+    pub fn step_over(&mut self) -> Stop {
+        let pc = self.vm.pc();
+        match disassemble_one(&self.vm.program, pc) {
+            Some((_, size)) => self.run_until(|next| next == pc + size),
+            None => self.step(),
+        }
+    }
+
+    pub fn cont(&mut self) -> Stop {
+        self.run_until(|_| false)
+    }
+
+    pub fn step_back(&mut self) -> Stop {
+        let stepped = self.vm.step_back();
+        self.stopped_at = Some(self.vm.pc());
+        if stepped {
+            self.halted = false;
+            Stop::Stepped
+        } else {
+            Stop::HistoryStart
+        }
+    }
+
+    pub fn reverse_cont(&mut self) -> Stop {
+        loop {
+            match self.step_back() {
+                Stop::Stepped => {
+                    let pc = self.vm.pc();
+                    if self.breakpoints.contains(&pc) {
+                        break Stop::Breakpoint(pc);
+                    }
+                }
+                stop => break stop,
+            }
+        }
+    }
+
+    fn run_until(&mut self, target: impl Fn(usize) -> bool) -> Stop {
+        // a breakpoint at the starting pc counts unless execution already stopped there
+        let pc = self.vm.pc();
+        if !self.halted && self.breakpoints.contains(&pc) && self.stopped_at != Some(pc) {
+            self.stopped_at = Some(pc);
+            return Stop::Breakpoint(pc);
+        }
+        loop {
+            match self.step() {
+                Stop::Stepped => {
+                    let pc = self.vm.pc();
+                    if self.breakpoints.contains(&pc) {
+                        break Stop::Breakpoint(pc);
+                    }
+                    if target(pc) {
+                        break Stop::Stepped;
+                    }
+                }
+                stop => break stop,
+            }
+        }
+    }
+
+    fn current_instruction(&self) -> String {
+        let pc = self.vm.pc();
+        let text = disassemble_one(&self.vm.program, pc)
+            .map(|(text, _)| text)
+            .unwrap_or_else(|| "<end of program>".to_string());
+        format!("{:#06X}: {:<16} at {}", pc, text, self.vm.location(pc))
+    }
+
+    fn report(&self, stop: Stop, out: &mut impl Write) -> io::Result<()> {
+        match stop {
+            Stop::Stepped => writeln!(out, "{}", self.current_instruction()),
+            Stop::Breakpoint(_) => {
+                writeln!(out, "breakpoint hit")?;
+                writeln!(out, "{}", self.current_instruction())
+            }
+            Stop::Watchpoint(hit) => {
+                writeln!(
+                    out,
+                    "watchpoint {} written at {}",
+                    hit,
+                    self.vm.location(hit.pc())
+                )?;
+                writeln!(out, "{}", self.current_instruction())
+            }
+            Stop::Halted => match self.vm.exit_code() {
+                0 => writeln!(out, "program halted"),
+                code => writeln!(out, "program exited with status {}", code),
+            },
+            Stop::HistoryStart => {
+                writeln!(out, "reached the start of recorded history")?;
+                writeln!(out, "{}", self.current_instruction())
+            }
+            Stop::Error(code) => {
+                writeln!(out, "error {}: {}", code, self.vm.error_message(code))
+            }
+        }
+    }
+
+    fn print(&self, what: &str, out: &mut impl Write) -> io::Result<()> {
+        match what {
+            "pc" => writeln!(out, "pc = {}", self.vm.pc()),
+            "flags" => writeln!(out, "equal_flag = {}", self.vm.equal_flag()),
+            "remainder" => writeln!(out, "remainder = {}", self.vm.remainder()),
+            _ => match Register::parse(what) {
+                Some(reg) => writeln!(out, "{} = {}", reg, self.vm.register_value(reg)),
+                None => writeln!(out, "unknown register or state `{}`", what),
+            },
+        }
+    }
+
+    fn print_registers(&self, out: &mut impl Write) -> io::Result<()> {
+        for row in self.vm.registers.chunks(4).enumerate() {
+            let (index, values) = row;
+            let line: Vec<String> = values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| format!("${:<2} = {:<11}", index * 4 + i, value))
+                .collect();
+            writeln!(out, "{}", line.join(" ").trim_end())?;
+        }
+        Ok(())
+    }
+
+    fn print_disassembly(&self, out: &mut impl Write) -> io::Result<()> {
+        let listing = disassemble(&self.vm.program);
+        let pc = self.vm.pc();
+        let current = listing
+            .iter()
+            .position(|(address, _)| *address >= pc)
+            .unwrap_or(listing.len());
+        let start = current.saturating_sub(DISASSEMBLY_CONTEXT);
+        let end = (current + DISASSEMBLY_CONTEXT + 1).min(listing.len());
+        for (address, text) in &listing[start..end] {
+            let marker = if *address == pc { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(address) {
+                "*"
+            } else {
+                " "
+            };
+            writeln!(out, "{}{} {:#06X}: {}", marker, breakpoint, address, text)?;
+        }
+        Ok(())
+    }
+
+    /// Executes a single debugger command. Returns false once the user asked to quit
+    pub fn handle(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(true),
+        };
+        let argument = words.next();
+        match (command, argument) {
+            ("quit", _) | ("q", _) => return Ok(false),
+            ("help", _) | ("h", _) => writeln!(out, "{}", HELP)?,
+            ("break", Some(target)) | ("b", Some(target)) => match self.add_breakpoint(target) {
+                Ok(address) => writeln!(out, "breakpoint set at {}", self.vm.location(address))?,
+                Err(err) => writeln!(out, "{}", err)?,
+            },
+            ("delete", Some(target)) | ("d", Some(target)) => {
+                match self.remove_breakpoint(target) {
+                    Ok(address) => writeln!(out, "breakpoint removed at {:#06X}", address)?,
+                    Err(err) => writeln!(out, "{}", err)?,
+                }
+            }
+            ("breakpoints", _) => {
+                for address in &self.breakpoints {
+                    writeln!(out, "{:#06X} at {}", address, self.vm.location(*address))?;
+                }
+            }
+            ("watch", Some("mem")) => {
+                let start = words.next().and_then(parse_address);
+                let len = words.next().and_then(|len| len.parse::<usize>().ok());
+                match (start, len) {
+                    (Some(start), Some(len)) if len > 0 => {
+                        self.vm
+                            .add_memory_watchpoint(start, len, watch_kind(words.next()));
+                        writeln!(out, "watching {} bytes at {:#06X}", len, start)?
+                    }
+                    _ => writeln!(out, "usage: watch mem <addr> <len> [change]")?,
+                }
+            }
+            ("unwatch", Some("mem")) => match words.next().and_then(parse_address) {
+                Some(start) if self.vm.remove_memory_watchpoint(start) => {
+                    writeln!(out, "watchpoint removed for {:#06X}", start)?
+                }
+                _ => writeln!(out, "no memory watchpoint at that address")?,
+            },
+            ("watch", Some(register)) => match Register::parse(register) {
+                Some(register) => {
+                    self.vm.add_watchpoint(register, watch_kind(words.next()));
+                    writeln!(out, "watching {}", register)?
+                }
+                None => writeln!(out, "unknown register `{}`", register)?,
+            },
+            ("unwatch", Some(register)) => match Register::parse(register) {
+                Some(register) if self.vm.remove_watchpoint(register) => {
+                    writeln!(out, "watchpoint removed for {}", register)?
+                }
+                _ => writeln!(out, "no watchpoint for `{}`", register)?,
+            },
+            ("step", _) | ("s", _) => {
+                let stop = self.step();
+                self.report(stop, out)?
+            }
+            ("next", _) | ("n", _) => {
+                let stop = self.step_over();
+                self.report(stop, out)?
+            }
+            ("continue", _) | ("c", _) => {
+                let stop = self.cont();
+                self.report(stop, out)?
+            }
+            ("step-back", _) | ("sb", _) => {
+                let stop = self.step_back();
+                self.report(stop, out)?
+            }
+            ("reverse-continue", _) | ("rc", _) => {
+                let stop = self.reverse_cont();
+                self.report(stop, out)?
+            }
+            ("registers", _) | ("regs", _) => self.print_registers(out)?,
+            ("print", Some(what)) | ("p", Some(what)) => self.print(what, out)?,
+            ("disas", _) => self.print_disassembly(out)?,
+            _ => writeln!(out, "unknown command `{}`, try `help`", line.trim())?,
+        }
+        Ok(true)
+    }
+
+    pub fn run(&mut self) {
+        let stdin = io::stdin();
+        let mut stdout = io::stdout();
+        writeln!(stdout, "{}", self.current_instruction()).expect("failed to write stdout");
+        loop {
+            print!("(debug) ");
+            stdout.flush().expect("failed to flush stdout");
+
+            let mut buffer = String::new();
+            if stdin
+                .lock()
+                .read_line(&mut buffer)
+                .expect("failed to read stdin")
+                == 0
+            {
+                break;
+            }
+            if !self
+                .handle(&buffer, &mut stdout)
+                .expect("failed to write stdout")
+            {
+                break;
+            }
+        }
+    }
+}
+
+// decimal or 0x prefixed hex
+fn parse_address(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// `change` only stops on writes of a different value
+fn watch_kind(word: Option<&str>) -> WatchKind {
+    match word {
+        Some("change") => WatchKind::Change,
+        _ => WatchKind::Write,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::vm::Value;
+
+    // counts $0 up to 3
+    const LOOP: &str = "load $0 #0
+load $1 #1
+load $2 #3
+load $3 #16
+loop: add $0 $1 $0
+neq $0 $2
+jeq $3
+done: hlt
+";
+
+    fn debugger() -> Debugger {
+        let mut vm = VM::new();
+        vm.load(assemble("loop.s", LOOP).unwrap()).unwrap();
+        Debugger::new(vm)
+    }
+
+    #[test]
+    fn test_breakpoint_at_label() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.add_breakpoint("loop"), Ok(16));
+        assert_eq!(debugger.add_breakpoint("0x19"), Ok(25));
+        assert!(debugger.add_breakpoint("nope").is_err());
+
+        assert_eq!(debugger.cont(), Stop::Breakpoint(16));
+        assert_eq!(debugger.vm().registers[0], 0);
+        assert_eq!(debugger.cont(), Stop::Breakpoint(16));
+        assert_eq!(debugger.vm().registers[0], 1);
+
+        assert_eq!(debugger.remove_breakpoint("loop"), Ok(16));
+        assert_eq!(debugger.cont(), Stop::Breakpoint(25));
+        assert_eq!(debugger.vm().registers[0], 3);
+        assert_eq!(debugger.cont(), Stop::Halted);
+        assert_eq!(debugger.step(), Stop::Halted);
+    }
+
+    #[test]
+    fn test_breakpoint_at_start() {
+        let mut debugger = debugger();
+        debugger.add_breakpoint("0").unwrap();
+        assert_eq!(debugger.cont(), Stop::Breakpoint(0));
+        assert_eq!(debugger.vm().executed(), 0);
+        debugger.add_breakpoint("loop").unwrap();
+        assert_eq!(debugger.cont(), Stop::Breakpoint(16));
+
+        assert_eq!(debugger.reverse_cont(), Stop::Breakpoint(0));
+        assert_eq!(debugger.cont(), Stop::Breakpoint(16));
+    }
+
+    #[test]
+    fn test_step_and_step_over() {
+        let mut debugger = debugger();
+        assert_eq!(debugger.step(), Stop::Stepped);
+        assert_eq!(debugger.vm().pc(), 4);
+        debugger.add_breakpoint("23").unwrap();
+        assert_eq!(debugger.cont(), Stop::Breakpoint(23));
+        debugger.remove_breakpoint("23").unwrap();
+        // steps over the whole loop
+        assert_eq!(debugger.step_over(), Stop::Stepped);
+        assert_eq!(debugger.vm().pc(), 25);
+        assert_eq!(debugger.vm().registers[0], 3);
+    }
+
+    #[test]
+    fn test_reverse_execution() {
+        let mut debugger = debugger();
+        debugger.add_breakpoint("loop").unwrap();
+        assert_eq!(debugger.cont(), Stop::Breakpoint(16));
+        debugger.remove_breakpoint("loop").unwrap();
+        assert_eq!(debugger.cont(), Stop::Halted);
+        assert_eq!(debugger.vm().registers[0], 3);
+
+        assert_eq!(debugger.step_back(), Stop::Stepped);
+        assert_eq!(debugger.vm().pc(), 25);
+        debugger.add_breakpoint("loop").unwrap();
+        assert_eq!(debugger.reverse_cont(), Stop::Breakpoint(16));
+        assert_eq!(debugger.vm().registers[0], 2);
+        assert_eq!(debugger.reverse_cont(), Stop::Breakpoint(16));
+        assert_eq!(debugger.vm().registers[0], 1);
+        assert_eq!(debugger.reverse_cont(), Stop::Breakpoint(16));
+        assert_eq!(debugger.vm().registers[0], 0);
+        assert_eq!(debugger.reverse_cont(), Stop::HistoryStart);
+        assert_eq!(debugger.vm().pc(), 0);
+
+        // runs forward again after rewinding
+        assert_eq!(debugger.cont(), Stop::Breakpoint(16));
+        assert_eq!(debugger.step(), Stop::Stepped);
+        assert_eq!(debugger.vm().registers[0], 1);
+    }
+
+    #[test]
+    fn test_watchpoint_stop() {
+        let mut debugger = debugger();
+        let mut out = vec![];
+        debugger.handle("watch $0 change", &mut out).unwrap();
+        assert_eq!(
+            debugger.cont(),
+            Stop::Watchpoint(WatchHit::Register {
+                register: Register::Int(0),
+                old: Value::Int(0),
+                new: Value::Int(1),
+                pc: 16
+            })
+        );
+        debugger.handle("unwatch $0", &mut out).unwrap();
+        assert_eq!(debugger.cont(), Stop::Halted);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "watching $0\nwatchpoint removed for $0\n"
+        );
+    }
+
+    #[test]
+    fn test_memory_watchpoint_stop() {
+        let mut vm = VM::builder().memory_size(16).quiet().build();
+        vm.load(assemble("mem.s", "load $0 #0\nvst $v0 $0\nhlt\n").unwrap())
+            .unwrap();
+        vm.vector_registers[0] = [0x0102, 0, 0, 0];
+        let mut debugger = Debugger::new(vm);
+        let mut out = vec![];
+        debugger.handle("watch mem 0x2 2", &mut out).unwrap();
+        debugger.handle("watch mem 4", &mut out).unwrap();
+        debugger.handle("continue", &mut out).unwrap();
+        debugger.handle("unwatch mem 2", &mut out).unwrap();
+        debugger.handle("unwatch mem 2", &mut out).unwrap();
+        assert_eq!(debugger.cont(), Stop::Halted);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "watching 2 bytes at 0x0002\n\
+             usage: watch mem <addr> <len> [change]\n\
+             watchpoint mem[0x0002]: 00 00 -> 01 02 written at mem.s:2\n\
+             0x0007: HLT              at mem.s:3\n\
+             watchpoint removed for 0x0002\n\
+             no memory watchpoint at that address\n"
+        );
+    }
+
+    #[test]
+    fn test_error_stop() {
+        let mut vm = VM::new();
+        vm.load(assemble("bad.s", "load $0 #1\nigl\n").unwrap())
+            .unwrap();
+        let mut debugger = Debugger::new(vm);
+        assert_eq!(debugger.cont(), Stop::Error(2));
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut debugger = debugger();
+        let mut out = vec![];
+        for command in &["b loop", "c", "p $0", "p pc", "s", "disas", "foo"] {
+            assert!(debugger.handle(command, &mut out).unwrap());
+        }
+        assert!(!debugger.handle("quit", &mut out).unwrap());
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out,
+            "breakpoint set at loop.s:5 (in `loop`)
+breakpoint hit
+0x0010: ADD $0 $1 $0     at loop.s:5 (in `loop`)
+$0 = 0
+pc = 16
+0x0014: NEQ $0 $2        at loop.s:6 (in `loop`)
+    0x0008: LOAD $2 #3
+    0x000C: LOAD $3 #16
+  * 0x0010: ADD $0 $1 $0
+=>  0x0014: NEQ $0 $2
+    0x0017: JEQ $3
+    0x0019: HLT
+unknown command `foo`, try `help`
+"
+        );
+    }
+}
@@ -0,0 +1,82 @@
+use crate::instructions::Instruction;
+
+/// Decodes the instruction at `pc` into assembly text and its size in bytes
+pub fn disassemble_one(program: &[u8], pc: usize) -> Option<(String, usize)> {
+    Instruction::decode(program, pc).map(|(instruction, size)| (instruction.to_string(), size))
+}
+
+/// Decodes the whole program into (address, assembly text) pairs
+pub fn disassemble(program: &[u8]) -> Vec<(usize, String)> {
+    let mut result = vec![];
+    let mut pc = 0;
+    while pc < program.len() {
+        match disassemble_one(program, pc) {
+            Some((text, size)) => {
+                result.push((pc, text));
+                pc += size;
+            }
+            None => {
+                result.push((pc, "<truncated>".to_string()));
+                break;
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble_one() {
+        let program = vec![1, 0, 1, 244, 2, 0, 1, 2, 6, 3, 0xA, 1, 2, 0, 200];
+        assert_eq!(
+            disassemble_one(&program, 0),
+            Some(("LOAD $0 #500".to_string(), 4))
+        );
+        assert_eq!(
+            disassemble_one(&program, 4),
+            Some(("ADD $0 $1 $2".to_string(), 4))
+        );
+        assert_eq!(
+            disassemble_one(&program, 8),
+            Some(("JMP $3".to_string(), 2))
+        );
+        assert_eq!(
+            disassemble_one(&program, 10),
+            Some(("EQ $1 $2".to_string(), 3))
+        );
+        assert_eq!(disassemble_one(&program, 13), Some(("HLT".to_string(), 1)));
+        assert_eq!(
+            disassemble_one(&program, 14),
+            Some(("IGL 0xC8".to_string(), 1))
+        );
+        assert_eq!(disassemble_one(&program, 15), None);
+    }
+
+    #[test]
+    fn test_disassemble_float() {
+        let image = crate::assembler::assemble("f.s", "loadf $f1 #-2.5\nftoi $f1 $3\n").unwrap();
+        assert_eq!(
+            disassemble(&image.code),
+            vec![
+                (0, "LOADF $f1 #-2.5".to_string()),
+                (10, "FTOI $f1 $3".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let program = vec![1, 0, 0, 1, 0, 1, 0];
+        assert_eq!(
+            disassemble(&program),
+            vec![
+                (0, "LOAD $0 #1".to_string()),
+                (4, "HLT".to_string()),
+                (5, "<truncated>".to_string()),
+            ]
+        );
+    }
+}
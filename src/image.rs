@@ -0,0 +1,208 @@
+use crate::debug_info::DebugInfo;
+
+/// Magic bytes every bytecode image starts with
+pub const MAGIC: &[u8; 4] = b"TOYV";
+/// Current version of the image layout. Version 2 added the file table of the debug section
+pub const VERSION: u8 = 2;
+
+// Section tags. Every section is encoded as <tag:u8> <len:u32> <payload>,
+// the list is terminated by SECTION_END. Unknown sections are skipped.
+const SECTION_END: u8 = 0;
+const SECTION_DEBUG: u8 = 1;
+const SECTION_DATA: u8 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum ImageError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    InvalidUtf8,
+}
+
+/// Assembled program: bytecode plus optional sections produced by the assembler
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Image {
+    pub code: Vec<u8>,
+    /// Initial memory contents, loaded at address 0
+    pub data: Vec<u8>,
+    pub debug_info: Option<DebugInfo>,
+}
+
+impl Image {
+    pub fn new(code: Vec<u8>) -> Self {
+        Image {
+            code,
+            data: vec![],
+            debug_info: None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        put_bytes(&mut out, &self.code);
+        if !self.data.is_empty() {
+            out.push(SECTION_DATA);
+            put_bytes(&mut out, &self.data);
+        }
+        if let Some(debug_info) = &self.debug_info {
+            out.push(SECTION_DEBUG);
+            put_bytes(&mut out, &debug_info.to_bytes());
+        }
+        out.push(SECTION_END);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ImageError> {
+        let mut reader = Reader::new(bytes);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+        match reader.u8()? {
+            VERSION => (),
+            version => return Err(ImageError::UnsupportedVersion(version)),
+        }
+        let mut image = Image::new(reader.bytes()?.to_vec());
+        loop {
+            match reader.u8()? {
+                SECTION_END => break Ok(image),
+                SECTION_DATA => image.data = reader.bytes()?.to_vec(),
+                SECTION_DEBUG => {
+                    image.debug_info = Some(DebugInfo::from_bytes(reader.bytes()?)?);
+                }
+                _ => {
+                    reader.bytes()?;
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn put_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+pub(crate) fn put_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+// length prefixed byte string
+pub(crate) fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+/// Cursor over a big-endian encoded buffer
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], ImageError> {
+        let end = self.pos.checked_add(n).ok_or(ImageError::Truncated)?;
+        let result = self.bytes.get(self.pos..end).ok_or(ImageError::Truncated)?;
+        self.pos = end;
+        Ok(result)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, ImageError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, ImageError> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, ImageError> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], ImageError> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn string(&mut self) -> Result<String, ImageError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| ImageError::InvalidUtf8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug_info::{LineEntry, Symbol};
+
+    #[test]
+    fn test_image_roundtrip() {
+        let mut image = Image::new(vec![1, 0, 1, 244, 0]);
+        let bytes = image.to_bytes();
+        assert_eq!(Image::from_bytes(&bytes), Ok(image.clone()));
+
+        image.debug_info = Some(DebugInfo {
+            files: vec!["loop.s".to_string()],
+            lines: vec![LineEntry {
+                start: 0,
+                end: 4,
+                line: 1,
+                file: 0,
+            }],
+            symbols: vec![Symbol {
+                name: "start".to_string(),
+                offset: 0,
+            }],
+        });
+        image.data = b"Hello\0".to_vec();
+        let bytes = image.to_bytes();
+        assert_eq!(Image::from_bytes(&bytes), Ok(image));
+    }
+
+    #[test]
+    fn test_image_errors() {
+        assert_eq!(Image::from_bytes(b"ELF"), Err(ImageError::Truncated));
+        assert_eq!(Image::from_bytes(b"ELF\x7f\x01"), Err(ImageError::BadMagic));
+        assert_eq!(
+            Image::from_bytes(b"TOYV\x09"),
+            Err(ImageError::UnsupportedVersion(9))
+        );
+        let mut bytes = Image::new(vec![0]).to_bytes();
+        bytes.pop();
+        assert_eq!(Image::from_bytes(&bytes), Err(ImageError::Truncated));
+    }
+
+    #[test]
+    fn test_image_old_version() {
+        // a version 1 image with a debug section naming a single file
+        let mut bytes = b"TOYV\x01".to_vec();
+        put_bytes(&mut bytes, &[0]);
+        bytes.push(SECTION_DEBUG);
+        let mut debug = vec![];
+        put_bytes(&mut debug, b"old.s");
+        put_u32(&mut debug, 0);
+        put_u32(&mut debug, 0);
+        put_bytes(&mut bytes, &debug);
+        bytes.push(SECTION_END);
+        assert_eq!(
+            Image::from_bytes(&bytes),
+            Err(ImageError::UnsupportedVersion(1))
+        );
+    }
+
+    #[test]
+    fn test_image_skips_unknown_sections() {
+        let mut bytes = Image::new(vec![0]).to_bytes();
+        bytes.pop();
+        bytes.push(0x42);
+        put_bytes(&mut bytes, b"future");
+        bytes.push(SECTION_END);
+        assert_eq!(Image::from_bytes(&bytes), Ok(Image::new(vec![0])));
+    }
+}
@@ -1,124 +1,433 @@
-#[derive(Debug, PartialEq)]
-pub enum Opcode {
-    HLT,  // Halt
-    LOAD, // Load
+use std::fmt;
+
+// Rust type of an operand field
+macro_rules! operand_type {
+    (Register) => {
+        u8
+    };
+    (FloatRegister) => {
+        u8
+    };
+    (VectorRegister) => {
+        u8
+    };
+    (Integer) => {
+        u16
+    };
+    (Float) => {
+        f64
+    };
+}
+
+// Generates `Opcode`, `Instruction` and their conversions from the opcode table below
+macro_rules! opcodes {
+    ($(
+        $opcode:ident = $byte:literal, $variant:ident { $($field:ident: $kind:ident),* },
+        cost $cost:literal, $description:literal;
+    )*) => {
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub enum Opcode {
+            $($opcode,)*
+            IGL(u8), // Illegal
+        }
+
+        /// Every opcode but IGL, in table order
+        pub const OPCODES: &[Opcode] = &[$(Opcode::$opcode),*];
+
+        /// Every mnemonic accepted by `Opcode::from(&str)`
+        pub const MNEMONICS: &[&str] = &[$(stringify!($opcode)),*];
+
+        impl From<u8> for Opcode {
+            fn from(v: u8) -> Self {
+                match v {
+                    $($byte => Opcode::$opcode,)*
+                    _ => Opcode::IGL(v),
+                }
+            }
+        }
+
+        impl From<&Opcode> for u8 {
+            fn from(op: &Opcode) -> Self {
+                match op {
+                    $(Opcode::$opcode => $byte,)*
+                    Opcode::IGL(_) => 0xFF,
+                }
+            }
+        }
+
+        impl From<&str> for Opcode {
+            fn from(opcode: &str) -> Self {
+                match opcode.to_uppercase().as_str() {
+                    $(stringify!($opcode) => Opcode::$opcode,)*
+                    _ => Opcode::IGL(0xFF),
+                }
+            }
+        }
+
+        impl Opcode {
+            /// Names and kinds of the operands, in encoding order
+            pub fn schema(&self) -> &'static [(&'static str, OperandKind)] {
+                match self {
+                    $(Opcode::$opcode => &[$((stringify!($field), OperandKind::$kind)),*],)*
+                    Opcode::IGL(_) => &[],
+                }
+            }
+
+            /// Price charged by a default `CostTable`
+            pub fn default_cost(&self) -> u64 {
+                match self {
+                    $(Opcode::$opcode => $cost,)*
+                    Opcode::IGL(_) => 1,
+                }
+            }
+
+            pub fn description(&self) -> &'static str {
+                match self {
+                    $(Opcode::$opcode => $description,)*
+                    Opcode::IGL(_) => "illegal opcode, fails the program",
+                }
+            }
+        }
+
+        /// A decoded instruction. Register operands are register numbers, in encoding order
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum Instruction {
+            $($variant { $($field: operand_type!($kind)),* },)*
+            Igl(u8),
+        }
+
+        impl Instruction {
+            fn build<S: OperandSource>(opcode: Opcode, s: &mut S) -> Option<Instruction> {
+                Some(match opcode {
+                    $(Opcode::$opcode => Instruction::$variant {
+                        $($field: match s.take(OperandKind::$kind)? {
+                            Operand::$kind(value) => value,
+                            _ => return None,
+                        },)*
+                    },)*
+                    Opcode::IGL(byte) => Instruction::Igl(byte),
+                })
+            }
+
+            // (opcode, operands in encoding order), the inverse of `build`
+            fn parts(&self) -> (Opcode, Vec<Operand>) {
+                match *self {
+                    $(Instruction::$variant { $($field),* } => {
+                        (Opcode::$opcode, vec![$(Operand::$kind($field)),*])
+                    })*
+                    Instruction::Igl(byte) => (Opcode::IGL(byte), vec![]),
+                }
+            }
+        }
+    };
+}
+
+// mnemonic = byte, instruction { operands in encoding order }, cost, description
+opcodes! {
+    HLT = 0x00, Hlt {}, cost 1, "stop the program with status 0";
+    EXIT = 0x19, Exit { code: Register }, cost 1, "stop the program with the status in $code";
+    LOAD = 0x01, Load { dst: Register, imm: Integer }, cost 1, "load a constant or label address";
     // math
-    ADD,
-    MUL,
-    SUB,
-    DIV,
+    ADD = 0x02, Add { lhs: Register, rhs: Register, dst: Register }, cost 1, "dst = lhs + rhs";
+    SUB = 0x03, Sub { lhs: Register, rhs: Register, dst: Register }, cost 1, "dst = lhs - rhs";
+    MUL = 0x04, Mul { lhs: Register, rhs: Register, dst: Register }, cost 1, "dst = lhs * rhs";
+    DIV = 0x05, Div { lhs: Register, rhs: Register, dst: Register }, cost 1,
+        "dst = lhs / rhs, keeping the remainder";
     // jumps
-    JMP,
-    JMPF,
-    JMPB,
-    JEQ,
+    JMP = 0x06, Jmp { target: Register }, cost 1, "jump to the address in $target";
+    JMPF = 0x07, Jmpf { offset: Register }, cost 1, "jump forward by $offset bytes";
+    JMPB = 0x08, Jmpb { offset: Register }, cost 1, "jump back by $offset bytes";
+    JEQ = 0x09, Jeq { target: Register }, cost 1, "jump to $target if the equal flag is set";
     // equality
-    EQ,
-    NEQ,
-    GT,
-    LT,
-    GTQ,
-    LTQ,
-    // utility
-    IGL(u8), // Illegal
-}
-
-impl From<u8> for Opcode {
-    fn from(v: u8) -> Self {
-        match v {
-            0 => Opcode::HLT,
-            1 => Opcode::LOAD,
-            2 => Opcode::ADD,
-            3 => Opcode::SUB,
-            4 => Opcode::MUL,
-            5 => Opcode::DIV,
-            6 => Opcode::JMP,
-            7 => Opcode::JMPF,
-            8 => Opcode::JMPB,
-            9 => Opcode::JEQ,
-            0xA => Opcode::EQ,
-            0xB => Opcode::NEQ,
-            0xC => Opcode::GT,
-            0xD => Opcode::LT,
-            0xE => Opcode::GTQ,
-            0xF => Opcode::LTQ,
-            _ => Opcode::IGL(v),
-        }
-    }
-}
-
-impl From<&Opcode> for u8 {
-    fn from(op: &Opcode) -> Self {
-        match op {
-            Opcode::HLT => 0,
-            Opcode::LOAD => 1,
-            Opcode::ADD => 2,
-            Opcode::SUB => 3,
-            Opcode::MUL => 4,
-            Opcode::DIV => 5,
-            Opcode::JMP => 6,
-            Opcode::JMPF => 7,
-            Opcode::JMPB => 8,
-            Opcode::JEQ => 9,
-            Opcode::EQ => 0xA,
-            Opcode::NEQ => 0xB,
-            Opcode::GT => 0xC,
-            Opcode::LT => 0xD,
-            Opcode::GTQ => 0xE,
-            Opcode::LTQ => 0xF,
-            Opcode::IGL(_) => 0xFF,
-        }
-    }
-}
-
-impl<'a> From<&str> for Opcode {
-    fn from(opcode: &str) -> Self {
-        match opcode.to_uppercase().as_str() {
-            "HLT" => Opcode::HLT,
-            "LOAD" => Opcode::LOAD,
-            "ADD" => Opcode::ADD,
-            "SUB" => Opcode::SUB,
-            "MUL" => Opcode::MUL,
-            "DIV" => Opcode::DIV,
-            "JMP" => Opcode::JMP,
-            "JMPF" => Opcode::JMPF,
-            "JMPB" => Opcode::JMPB,
-            "JEQ" => Opcode::JEQ,
-            "EQ" => Opcode::EQ,
-            "NEQ" => Opcode::NEQ,
-            "GT" => Opcode::GT,
-            "LT" => Opcode::LT,
-            "GTQ" => Opcode::GTQ,
-            "LTQ" => Opcode::LTQ,
-            _ => Opcode::IGL(0xFF),
-        }
-    }
-}
-
-// #[derive(Debug, PartialEq)]
-// pub struct Instruction {
-//     opcode: Opcode,
-// }
-
-// impl Instruction {
-//     fn new(opcode: Opcode) -> Instruction {
-//         Instruction { opcode: opcode }
-//     }
-// }
-
-// #[cfg(test)]
-// mod tests {
-//     use super::*;
-
-//     #[test]
-//     fn test_create_hlt() {
-//         let opcode = Opcode::HLT;
-//         assert_eq!(opcode, Opcode::HLT);
-//     }
-
-//     #[test]
-//     fn test_create_instruction() {
-//         let instruction = Instruction::new(Opcode::HLT);
-//         assert_eq!(instruction.opcode, Opcode::HLT);
-//     }
-// }
+    EQ = 0x0A, Eq { lhs: Register, rhs: Register }, cost 1, "equal flag = lhs == rhs";
+    NEQ = 0x0B, Neq { lhs: Register, rhs: Register }, cost 1, "equal flag = lhs != rhs";
+    GT = 0x0C, Gt { lhs: Register, rhs: Register }, cost 1, "equal flag = lhs > rhs";
+    LT = 0x0D, Lt { lhs: Register, rhs: Register }, cost 1, "equal flag = lhs < rhs";
+    GTQ = 0x0E, Gtq { lhs: Register, rhs: Register }, cost 1, "equal flag = lhs >= rhs";
+    LTQ = 0x0F, Ltq { lhs: Register, rhs: Register }, cost 1, "equal flag = lhs <= rhs";
+    // memory and calls
+    ALOC = 0x10, Aloc { size: Register }, cost 1, "grow memory by $size zeroed bytes";
+    LDW = 0x1A, Ldw { addr: Register, dst: Register }, cost 1,
+        "load the big-endian word at $addr";
+    CALL = 0x11, Call { target: Register }, cost 1, "call the subroutine at $target";
+    RET = 0x12, Ret {}, cost 1, "return from the current subroutine";
+    // floating point
+    LOADF = 0x1B, Loadf { dst: FloatRegister, value: Float }, cost 1, "load a float constant";
+    FADD = 0x1C, Fadd { lhs: FloatRegister, rhs: FloatRegister, dst: FloatRegister }, cost 1,
+        "dst = lhs + rhs";
+    FSUB = 0x1D, Fsub { lhs: FloatRegister, rhs: FloatRegister, dst: FloatRegister }, cost 1,
+        "dst = lhs - rhs";
+    FMUL = 0x1E, Fmul { lhs: FloatRegister, rhs: FloatRegister, dst: FloatRegister }, cost 1,
+        "dst = lhs * rhs";
+    FDIV = 0x1F, Fdiv { lhs: FloatRegister, rhs: FloatRegister, dst: FloatRegister }, cost 1,
+        "dst = lhs / rhs";
+    FEQ = 0x20, Feq { lhs: FloatRegister, rhs: FloatRegister }, cost 1, "equal flag = lhs == rhs";
+    FLT = 0x21, Flt { lhs: FloatRegister, rhs: FloatRegister }, cost 1, "equal flag = lhs < rhs";
+    ITOF = 0x22, Itof { src: Register, dst: FloatRegister }, cost 1, "convert an integer to float";
+    FTOI = 0x23, Ftoi { src: FloatRegister, dst: Register }, cost 1,
+        "truncate a float to an integer";
+    // vectors
+    VLD = 0x24, Vld { addr: Register, dst: VectorRegister }, cost 1, "load 4 lanes from $addr";
+    VST = 0x25, Vst { src: VectorRegister, addr: Register }, cost 1, "store 4 lanes at $addr";
+    VADD = 0x26, Vadd { lhs: VectorRegister, rhs: VectorRegister, dst: VectorRegister }, cost 1,
+        "lane-wise dst = lhs + rhs";
+    VMUL = 0x27, Vmul { lhs: VectorRegister, rhs: VectorRegister, dst: VectorRegister }, cost 1,
+        "lane-wise dst = lhs * rhs";
+    VEQ = 0x28, Veq { lhs: VectorRegister, rhs: VectorRegister, dst: VectorRegister }, cost 1,
+        "lane-wise lhs == rhs mask";
+    VLT = 0x29, Vlt { lhs: VectorRegister, rhs: VectorRegister, dst: VectorRegister }, cost 1,
+        "lane-wise lhs < rhs mask";
+    VSUM = 0x2A, Vsum { src: VectorRegister, dst: Register }, cost 1, "sum of the lanes";
+    VSHUF = 0x2B, Vshuf { src: VectorRegister, dst: VectorRegister, selector: Integer }, cost 1,
+        "reorder lanes, 2 selector bits per lane";
+    // host calls
+    SYS = 0x13, Sys { number: Integer }, cost 1, "call a host function";
+    // console I/O
+    PRTI = 0x14, Prti { src: Register }, cost 1, "print an integer";
+    PRTC = 0x15, Prtc { src: Register }, cost 1, "print a character";
+    PRTS = 0x16, Prts { addr: Register }, cost 1, "print the string at $addr";
+    RDI = 0x17, Rdi { dst: Register }, cost 1, "read an integer line";
+    RDS = 0x18, Rds { addr: Register, len: Register }, cost 1,
+        "read a line of at most $len - 1 bytes to $addr";
+}
+
+/// Kind of an operand in an opcode's schema
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OperandKind {
+    Register,
+    FloatRegister,
+    VectorRegister,
+    Integer,
+    Float,
+}
+
+impl OperandKind {
+    /// Encoded size in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            OperandKind::Integer => 2,
+            OperandKind::Float => 8,
+            _ => 1,
+        }
+    }
+
+    // how an operand named `name` is written in assembly
+    fn syntax(&self, name: &str) -> String {
+        match self {
+            OperandKind::Register => format!("${}", name),
+            OperandKind::FloatRegister => format!("$f{}", name),
+            OperandKind::VectorRegister => format!("$v{}", name),
+            OperandKind::Integer | OperandKind::Float => format!("#{}", name),
+        }
+    }
+}
+
+impl Opcode {
+    /// Mnemonic and operands as written in assembly, e.g. `LOAD $dst #imm`
+    pub fn syntax(&self) -> String {
+        let mut syntax = format!("{:?}", self);
+        for (name, kind) in self.schema() {
+            syntax.push(' ');
+            syntax.push_str(&kind.syntax(name));
+        }
+        syntax
+    }
+}
+
+/// Value of an encoded operand
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Register(u8),
+    FloatRegister(u8),
+    VectorRegister(u8),
+    Integer(u16),
+    Float(f64),
+}
+
+impl Operand {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Operand::Register(register)
+            | Operand::FloatRegister(register)
+            | Operand::VectorRegister(register) => out.push(*register),
+            Operand::Integer(value) => out.extend_from_slice(&value.to_be_bytes()),
+            Operand::Float(value) => out.extend_from_slice(&value.to_bits().to_be_bytes()),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Register(register) => write!(f, "${}", register),
+            Operand::FloatRegister(register) => write!(f, "$f{}", register),
+            Operand::VectorRegister(register) => write!(f, "$v{}", register),
+            Operand::Integer(value) => write!(f, "#{}", value),
+            Operand::Float(value) => write!(f, "#{:?}", value),
+        }
+    }
+}
+
+// where `Instruction::build` takes its operands from
+trait OperandSource {
+    fn take(&mut self, kind: OperandKind) -> Option<Operand>;
+}
+
+// operands encoded in bytecode
+struct Bytes<'a> {
+    program: &'a [u8],
+    pos: usize,
+}
+
+impl OperandSource for Bytes<'_> {
+    fn take(&mut self, kind: OperandKind) -> Option<Operand> {
+        let bytes = self.program.get(self.pos..self.pos + kind.size())?;
+        self.pos += kind.size();
+        Some(match kind {
+            OperandKind::Register => Operand::Register(bytes[0]),
+            OperandKind::FloatRegister => Operand::FloatRegister(bytes[0]),
+            OperandKind::VectorRegister => Operand::VectorRegister(bytes[0]),
+            OperandKind::Integer => Operand::Integer(u16::from_be_bytes([bytes[0], bytes[1]])),
+            OperandKind::Float => {
+                let mut bits = [0; 8];
+                bits.copy_from_slice(bytes);
+                Operand::Float(f64::from_bits(u64::from_be_bytes(bits)))
+            }
+        })
+    }
+}
+
+// operands given by value, of the kinds `build` asks for
+impl<'a, I: Iterator<Item = &'a Operand>> OperandSource for I {
+    fn take(&mut self, _kind: OperandKind) -> Option<Operand> {
+        self.next().copied()
+    }
+}
+
+impl Instruction {
+    /// Decodes the instruction at `pc` and its size in bytes. `None` if it's truncated
+    pub fn decode(program: &[u8], pc: usize) -> Option<(Instruction, usize)> {
+        let opcode = Opcode::from(*program.get(pc)?);
+        let mut bytes = Bytes {
+            program,
+            pos: pc + 1,
+        };
+        let instruction = Instruction::build(opcode, &mut bytes)?;
+        Some((instruction, bytes.pos - pc))
+    }
+
+    /// Instruction of `opcode` with `operands`, `None` if they don't fit its layout
+    pub fn new(opcode: Opcode, operands: &[Operand]) -> Option<Instruction> {
+        let mut source = operands.iter();
+        let instruction = Instruction::build(opcode, &mut source)?;
+        match source.next() {
+            Some(_) => None,
+            None => Some(instruction),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let (opcode, operands) = self.parts();
+        // an illegal byte is kept as is
+        let mut out = match opcode {
+            Opcode::IGL(byte) => vec![byte],
+            _ => vec![u8::from(&opcode)],
+        };
+        for operand in &operands {
+            operand.encode(&mut out);
+        }
+        out
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        self.parts().0
+    }
+
+    pub fn operands(&self) -> Vec<Operand> {
+        self.parts().1
+    }
+}
+
+/// Assembly text of the instruction, e.g. `ADD $0 $1 $2`
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (opcode, operands) = self.parts();
+        match opcode {
+            Opcode::IGL(byte) => write!(f, "IGL {:#04X}", byte),
+            _ => write!(f, "{:?}", opcode),
+        }?;
+        for operand in operands {
+            write!(f, " {}", operand)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let instructions = vec![
+            Instruction::Load { dst: 3, imm: 500 },
+            Instruction::Add {
+                lhs: 0,
+                rhs: 1,
+                dst: 2,
+            },
+            Instruction::Loadf {
+                dst: 1,
+                value: -2.5,
+            },
+            Instruction::Vshuf {
+                src: 0,
+                dst: 4,
+                selector: 27,
+            },
+            Instruction::Ret {},
+            Instruction::Igl(200),
+        ];
+        let mut program = vec![];
+        for instruction in &instructions {
+            program.extend(instruction.encode());
+        }
+        assert_eq!(&program[..8], &[1, 3, 1, 244, 2, 0, 1, 2]);
+        let mut pc = 0;
+        for instruction in &instructions {
+            let (decoded, size) = Instruction::decode(&program, pc).unwrap();
+            assert_eq!(&decoded, instruction);
+            assert_eq!(size, instruction.encode().len());
+            pc += size;
+        }
+        assert_eq!(Instruction::decode(&program, pc), None);
+        assert_eq!(Instruction::decode(&[1, 3, 1], 0), None);
+    }
+
+    #[test]
+    fn test_new_and_display() {
+        let operands = [Operand::FloatRegister(1), Operand::Register(3)];
+        let instruction = Instruction::new(Opcode::FTOI, &operands).unwrap();
+        assert_eq!(instruction, Instruction::Ftoi { src: 1, dst: 3 });
+        assert_eq!(instruction.to_string(), "FTOI $f1 $3");
+        assert_eq!(instruction.operands(), operands.to_vec());
+        assert_eq!(Instruction::new(Opcode::FTOI, &operands[..1]), None);
+        assert_eq!(Instruction::new(Opcode::HLT, &operands), None);
+        assert_eq!(
+            Instruction::new(Opcode::FTOI, &[Operand::Register(1), Operand::Register(3)]),
+            None
+        );
+        assert_eq!(Instruction::Igl(200).to_string(), "IGL 0xC8");
+    }
+
+    #[test]
+    fn test_opcode_table() {
+        for opcode in OPCODES {
+            assert_eq!(Opcode::from(u8::from(opcode)), *opcode);
+            assert_eq!(Opcode::from(format!("{:?}", opcode).as_str()), *opcode);
+        }
+        assert_eq!(Opcode::LOAD.syntax(), "LOAD $dst #imm");
+        assert_eq!(Opcode::FTOI.syntax(), "FTOI $fsrc $dst");
+        assert_eq!(Opcode::HLT.syntax(), "HLT");
+    }
+}
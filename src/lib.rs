@@ -0,0 +1,48 @@
+//! Toy register VM with an assembler, debugger and REPL.
+//!
+//! The library API is the types re-exported here plus the `assembler`, `vm`, `image`,
+//! `instructions`, `observer`, `debug_info` and `disassembler` modules. The REPL and
+//! the debugger need the default `cli` feature.
+//!
+//! Embedding starts with [`Assembler`] and [`VM::builder`]:
+//!
+//! ```
+//! use toyvm::{Assembler, Step, VM};
+//!
+//! let image = Assembler::new()
+//!     .file("sum.s")
+//!     .assemble("load $0 #40\nload $1 #2\nadd $0 $1 $2\nhlt\n")
+//!     .unwrap();
+//! let mut vm = VM::builder().quiet().build();
+//! vm.load(image).unwrap();
+//! assert_eq!(vm.run(), Ok(Step::Done));
+//! assert_eq!(vm.registers()[2], 42);
+//! ```
+
+pub mod assembler;
+pub mod debug_info;
+pub mod disassembler;
+pub mod image;
+pub mod instructions;
+pub mod observer;
+mod profiler;
+mod trace;
+pub mod vm;
+
+// REPL and debugger of the `toyvm` binary, not part of the library API
+#[cfg(feature = "cli")]
+#[doc(hidden)]
+pub mod debugger;
+#[cfg(feature = "cli")]
+#[doc(hidden)]
+pub mod repl;
+
+extern crate nom;
+
+pub use crate::assembler::{Assembler, Diagnostic, Diagnostics};
+pub use crate::image::Image;
+pub use crate::instructions::Opcode;
+pub use crate::observer::Observer;
+pub use crate::profiler::Profile;
+pub use crate::trace::{MemoryWrite, RegisterWrite, TraceFormat, TraceRecord, Tracer};
+pub use crate::vm::{CostTable, Limits, Register, Step, VMBuilder, Value, VM};
@@ -1,11 +1,149 @@
-pub mod assembler;
-pub mod instructions;
-pub mod repl;
-pub mod vm;
+use std::fs;
+use std::io;
 
-extern crate nom;
+use toyvm::assembler::load;
+use toyvm::{debugger, repl, vm, TraceFormat, Tracer};
+
+const USAGE: &str = "usage: toyvm [debug <file> | run [--trace=jsonl|text] [--profile] \
+    [--profile-folded=<out>] [--profile-chrome=<out>] [--env=<name>]... <file> [<arg>...]]";
+
+// Process statuses are split into ranges so that scripts can tell them apart:
+// 0..=99 is the program's own EXIT status, 100 a toolchain error (usage, a file that
+// can't be read, assembled or loaded), 101 is left to Rust panics and 200 + code a VM fault
+
+/// Status of errors found before the program runs
+const TOOL_STATUS: i32 = 100;
+/// Process status of a VM fault is this plus the error code
+const FAULT_STATUS_BASE: i32 = 200;
+
+fn exit_status(vm: &vm::VM, result: &Result<vm::Step, u8>) -> i32 {
+    match result {
+        Ok(_) => vm.exit_code(),
+        Err(code) => FAULT_STATUS_BASE + i32::from(*code),
+    }
+}
+
+fn debug(path: &str) -> Result<(), String> {
+    let mut vm = vm::VM::builder().quiet().build();
+    vm.load(load(path)?)
+        .map_err(|code| format!("exit {}: {}", code, vm.error_message(code)))?;
+    debugger::Debugger::new(vm).run();
+    Ok(())
+}
+
+/// Runs a program and returns the process status it maps to
+fn run(args: &[&str]) -> Result<i32, String> {
+    let mut path = None;
+    let mut vm = vm::VM::builder().quiet().build();
+    let mut report = false;
+    let mut folded = None;
+    let mut chrome_trace = None;
+    let mut env = vec![];
+    let mut args = args.iter();
+    // options come before the file, everything after it is passed to the program
+    for arg in &mut args {
+        if *arg == "--profile" {
+            report = true;
+        } else if let Some(format) = arg.strip_prefix("--trace=") {
+            let format = TraceFormat::parse(format)
+                .ok_or_else(|| format!("unknown trace format `{}`", format))?;
+            vm.set_tracer(Some(Tracer::new(format, Box::new(io::stderr()))));
+        } else if let Some(file) = arg.strip_prefix("--profile-folded=") {
+            folded = Some(file);
+        } else if let Some(file) = arg.strip_prefix("--profile-chrome=") {
+            chrome_trace = Some(file);
+        } else if let Some(name) = arg.strip_prefix("--env=") {
+            // unset variables are left out
+            if let Ok(value) = std::env::var(name) {
+                env.push(format!("{}={}", name, value));
+            }
+        } else {
+            path = Some(*arg);
+            break;
+        }
+    }
+    vm.set_profiling(report || folded.is_some() || chrome_trace.is_some());
+    let path = path.ok_or(USAGE)?;
+    let bytes = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+    if bytes.starts_with(vm::SNAPSHOT_MAGIC) {
+        // resume a saved machine, its memory already holds the arguments
+        if args.len() > 0 || !env.is_empty() {
+            return Err(format!("{}: a snapshot can't take arguments", path));
+        }
+        vm.restore(&bytes)
+            .map_err(|err| format!("{}: {:?}", path, err))?;
+    } else {
+        vm.load(load(path)?)
+            .map_err(|code| format!("exit {}: {}", code, vm.error_message(code)))?;
+        let argv: Vec<&str> = std::iter::once(path).chain(args.copied()).collect();
+        let env: Vec<&str> = env.iter().map(String::as_str).collect();
+        vm.set_args(&argv, &env);
+    }
+    let result = vm.run();
+    if let Some(profile) = vm.profile() {
+        let debug_info = vm.debug_info();
+        if report {
+            eprint!("{}", profile.report(vm.program(), debug_info));
+        }
+        if let Some(file) = folded {
+            fs::write(file, profile.folded(vm.program(), debug_info))
+                .map_err(|err| format!("{}: {}", file, err))?;
+        }
+        if let Some(file) = chrome_trace {
+            fs::write(file, profile.chrome_trace()).map_err(|err| format!("{}: {}", file, err))?;
+        }
+    }
+    if let Err(code) = result {
+        eprintln!("exit {}: {}", code, vm.error_message(code));
+    }
+    Ok(exit_status(&vm, &result))
+}
 
 fn main() {
-    let mut r = repl::REPL::new();
-    r.run();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        // the REPL is dropped before exiting so that its history gets saved
+        [] => match repl::REPL::new().map(|mut repl| repl.run()) {
+            Ok(0) => Ok(()),
+            Ok(status) => std::process::exit(status),
+            Err(err) => Err(err),
+        },
+        ["debug", path] => debug(path),
+        ["run", args @ ..] => match run(args) {
+            Ok(0) => Ok(()),
+            Ok(status) => std::process::exit(status),
+            Err(err) => Err(err),
+        },
+        _ => Err(USAGE.to_string()),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        std::process::exit(TOOL_STATUS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toyvm::{Assembler, Step};
+
+    fn status(source: &str) -> i32 {
+        let mut vm = vm::VM::builder().quiet().build();
+        vm.load(Assembler::new().assemble(source).unwrap()).unwrap();
+        let result = vm.run();
+        exit_status(&vm, &result)
+    }
+
+    #[test]
+    fn test_exit_status() {
+        assert_eq!(status("hlt\n"), 0);
+        assert_eq!(status("load $0 #99\nexit $0\n"), 99);
+        assert_eq!(status("load $0 #100\nexit $0\n"), 215);
+        assert_eq!(status("load $0 #256\nexit $0\n"), 215);
+        assert_eq!(status("load $0 #1\n"), 201);
+        assert_eq!(status("igl\n"), 202);
+        assert_eq!(exit_status(&vm::VM::new(), &Ok(Step::Done)), 0);
+        assert_eq!(exit_status(&vm::VM::new(), &Err(2)), 202);
+    }
 }
@@ -0,0 +1,221 @@
+use std::io;
+use std::io::Write;
+
+use crate::instructions::Opcode;
+use crate::vm::{Vector, VM};
+
+/// Hooks the VM calls while executing. Every hook gets read-only access to the VM;
+/// all of them do nothing by default. Observers are `Send` like the VM owning them
+pub trait Observer: Send {
+    /// Called before the instruction at `pc` is executed
+    fn before_instruction(&mut self, _vm: &VM, _pc: usize, _opcode: &Opcode) {}
+
+    /// Called once the instruction at `pc` has executed successfully
+    fn after_instruction(&mut self, _vm: &VM, _pc: usize, _opcode: &Opcode) {}
+
+    /// Called when `HLT` or `EXIT` at `pc` stops the program, see `VM::exit_code`
+    fn on_halt(&mut self, _vm: &VM, _pc: usize) {}
+
+    /// Called when execution fails with `code`. `message` describes the failure
+    fn on_error(&mut self, _vm: &VM, _code: u8, _message: &str) {}
+
+    /// Called for every register write, including writes of an unchanged value
+    fn on_register_write(&mut self, _vm: &VM, _register: usize, _old: i32, _new: i32) {}
+
+    /// Called for every write of a `$fN` register
+    fn on_float_register_write(&mut self, _vm: &VM, _register: usize, _old: f64, _new: f64) {}
+
+    /// Called for every write of a `$vN` register
+    fn on_vector_register_write(&mut self, _vm: &VM, _register: usize, _old: Vector, _new: Vector) {
+    }
+
+    /// Called when `VST` or `RDS` stores `new` over `old` at `address`
+    fn on_memory_write(&mut self, _vm: &VM, _address: usize, _old: &[u8], _new: &[u8]) {}
+}
+
+/// Prints halts and errors, e.g. `HLT encountered`. Installed in every new VM
+pub struct ConsoleObserver {
+    out: Box<dyn Write + Send>,
+}
+
+impl ConsoleObserver {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        ConsoleObserver { out }
+    }
+}
+
+impl Default for ConsoleObserver {
+    fn default() -> Self {
+        ConsoleObserver::new(Box::new(io::stdout()))
+    }
+}
+
+// messages are best effort, a broken output must not stop the program
+impl Observer for ConsoleObserver {
+    fn on_halt(&mut self, vm: &VM, _pc: usize) {
+        let _ = match vm.exit_code() {
+            0 => writeln!(self.out, "HLT encountered"),
+            code => writeln!(self.out, "EXIT {} encountered", code),
+        };
+    }
+
+    fn on_error(&mut self, _vm: &VM, _code: u8, message: &str) {
+        let _ = writeln!(self.out, "{}", message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::vm::Step;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Observer for Recorder {
+        fn before_instruction(&mut self, vm: &VM, pc: usize, opcode: &Opcode) {
+            assert_eq!(vm.pc(), pc);
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("before {} {:?}", pc, opcode));
+        }
+
+        fn after_instruction(&mut self, vm: &VM, pc: usize, opcode: &Opcode) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("after {} {:?} -> {}", pc, opcode, vm.pc()));
+        }
+
+        fn on_halt(&mut self, _vm: &VM, pc: usize) {
+            self.0.lock().unwrap().push(format!("halt {}", pc));
+        }
+
+        fn on_error(&mut self, _vm: &VM, code: u8, message: &str) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("error {}: {}", code, message));
+        }
+
+        fn on_register_write(&mut self, vm: &VM, register: usize, old: i32, new: i32) {
+            assert_eq!(vm.registers[register], new);
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("write ${} {} -> {}", register, old, new));
+        }
+
+        fn on_float_register_write(&mut self, vm: &VM, register: usize, old: f64, new: f64) {
+            assert_eq!(vm.float_registers[register], new);
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("write $f{} {} -> {}", register, old, new));
+        }
+
+        fn on_vector_register_write(
+            &mut self,
+            _vm: &VM,
+            register: usize,
+            old: Vector,
+            new: Vector,
+        ) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("write $v{} {:?} -> {:?}", register, old, new));
+        }
+
+        fn on_memory_write(&mut self, vm: &VM, address: usize, old: &[u8], new: &[u8]) {
+            assert_eq!(&vm.memory()[address..address + new.len()], new);
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("store {} {:?} -> {:?}", address, old, new));
+        }
+    }
+
+    #[test]
+    fn test_observer_hooks() {
+        let recorder = Recorder::default();
+        let mut vm = VM::new();
+        vm.clear_observers();
+        vm.add_observer(Box::new(recorder.clone()));
+        vm.load(assemble("test.s", "load $0 #7\nloadf $f1 #2.5\nhlt\n").unwrap())
+            .unwrap();
+        assert_eq!(vm.run(), Ok(Step::Done));
+        assert_eq!(vm.run_once(), Err(1));
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                "before 0 LOAD",
+                "write $0 0 -> 7",
+                "after 0 LOAD -> 4",
+                "before 4 LOADF",
+                "write $f1 0 -> 2.5",
+                "after 4 LOADF -> 14",
+                "before 14 HLT",
+                "halt 14",
+                "after 14 HLT -> 15",
+                "error 1: pc overflow at pc 15",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_observer_vector_and_memory_writes() {
+        let recorder = Recorder::default();
+        let mut vm = VM::builder().memory_size(32).quiet().build();
+        vm.add_observer(Box::new(recorder.clone()));
+        vm.load(assemble("test.s", "vld $0 $v1\nload $2 #16\nvst $v1 $2\nhlt\n").unwrap())
+            .unwrap();
+        assert_eq!(vm.run(), Ok(Step::Done));
+        let events: Vec<String> = recorder
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.starts_with("write $v") || event.starts_with("store"))
+            .cloned()
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                "write $v1 [0, 0, 0, 0] -> [0, 0, 0, 0]".to_string(),
+                format!("store 16 {:?} -> {:?}", [0u8; 16], [0u8; 16]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_console_observer() {
+        let out = Arc::new(Mutex::new(vec![]));
+        struct Shared(Arc<Mutex<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut vm = VM::new();
+        vm.clear_observers();
+        vm.add_observer(Box::new(ConsoleObserver::new(Box::new(Shared(
+            out.clone(),
+        )))));
+        vm.program = vec![0, 200];
+        assert_eq!(vm.run_once(), Ok(Step::Done));
+        assert_eq!(vm.run_once(), Err(2));
+        assert_eq!(
+            String::from_utf8(out.lock().unwrap().clone()).unwrap(),
+            "HLT encountered\nIGL 200 encountered at pc 1\n"
+        );
+    }
+}
@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+
+use crate::debug_info::DebugInfo;
+use crate::disassembler::disassemble_one;
+use crate::instructions::Opcode;
+use crate::trace::escape_json;
+
+/// Number of rows printed per table of the hot-spot report
+const REPORT_ROWS: usize = 10;
+
+/// Label reported for instructions not preceded by any label
+pub const NO_LABEL: &str = "<no label>";
+
+/// Contiguous run of instructions executed under the same label.
+/// Time is measured in executed instructions
+#[derive(Debug, PartialEq, Clone)]
+pub struct Region {
+    pub label: String,
+    pub start: u64,
+    pub duration: u64,
+}
+
+/// Execution counts collected while the VM runs
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Profile {
+    pub total: u64,
+    pub opcodes: BTreeMap<String, u64>,
+    pub addresses: BTreeMap<usize, u64>,
+    pub regions: Vec<Region>,
+}
+
+impl Profile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts an executed instruction. `label` is the closest label before `pc`, if known
+    pub fn record(&mut self, pc: usize, opcode: &Opcode, label: Option<&str>) {
+        let name = match opcode {
+            Opcode::IGL(_) => "IGL".to_string(),
+            _ => format!("{:?}", opcode),
+        };
+        *self.opcodes.entry(name).or_insert(0) += 1;
+        *self.addresses.entry(pc).or_insert(0) += 1;
+
+        let label = label.unwrap_or(NO_LABEL);
+        match self.regions.last_mut() {
+            Some(region) if region.label == label => region.duration += 1,
+            _ => self.regions.push(Region {
+                label: label.to_string(),
+                start: self.total,
+                duration: 1,
+            }),
+        }
+        self.total += 1;
+    }
+
+    /// Totals per label, attributing every address to the closest preceding label
+    pub fn labels(&self, debug_info: &DebugInfo) -> BTreeMap<String, u64> {
+        let mut labels = BTreeMap::new();
+        for (&pc, &count) in &self.addresses {
+            let label = debug_info
+                .symbol(pc)
+                .map(|symbol| symbol.name.as_str())
+                .unwrap_or(NO_LABEL);
+            *labels.entry(label.to_string()).or_insert(0) += count;
+        }
+        labels
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            count as f64 * 100.0 / self.total as f64
+        }
+    }
+
+    /// Hot-spot report: hottest opcodes, addresses and labels
+    pub fn report(&self, program: &[u8], debug_info: Option<&DebugInfo>) -> String {
+        let mut report = format!("total instructions: {}\n", self.total);
+
+        report.push_str("opcodes:\n");
+        for (name, count) in hottest(&self.opcodes) {
+            report.push_str(&format!(
+                "  {:<8} {:>10} {:>6.2}%\n",
+                name,
+                count,
+                self.percent(count)
+            ));
+        }
+
+        report.push_str("addresses:\n");
+        for (pc, count) in hottest(&self.addresses) {
+            let instruction = disassemble_one(program, pc)
+                .map(|(text, _)| text)
+                .unwrap_or_default();
+            let location = debug_info
+                .and_then(|debug_info| debug_info.location(pc))
+                .unwrap_or_default();
+            report.push_str(
+                format!(
+                    "  {:#06X} {:>10} {:>6.2}%  {:<16} {}\n",
+                    pc,
+                    count,
+                    self.percent(count),
+                    instruction,
+                    location
+                )
+                .trim_end(),
+            );
+            report.push('\n');
+        }
+
+        if let Some(debug_info) = debug_info {
+            report.push_str("labels:\n");
+            for (label, count) in hottest(&self.labels(debug_info)) {
+                report.push_str(&format!(
+                    "  {:<16} {:>10} {:>6.2}%\n",
+                    label,
+                    count,
+                    self.percent(count)
+                ));
+            }
+        }
+        report
+    }
+
+    /// Folded stacks (`file;label;instruction count`) as consumed by flamegraph tools
+    pub fn folded(&self, program: &[u8], debug_info: Option<&DebugInfo>) -> String {
+        let mut folded = String::new();
+        for (&pc, count) in &self.addresses {
+            let root = debug_info
+                .and_then(|debug_info| debug_info.file(pc))
+                .unwrap_or("program");
+            let label = debug_info
+                .and_then(|debug_info| debug_info.symbol(pc))
+                .map(|symbol| symbol.name.as_str())
+                .unwrap_or(NO_LABEL);
+            let instruction = disassemble_one(program, pc)
+                .map(|(text, _)| text)
+                .unwrap_or_default();
+            folded.push_str(&format!(
+                "{};{};{:#06X} {} {}\n",
+                root, label, pc, instruction, count
+            ));
+        }
+        folded
+    }
+
+    /// Chrome Trace Event JSON with a complete event per label region.
+    /// Every executed instruction accounts for one microsecond
+    pub fn chrome_trace(&self) -> String {
+        let events: Vec<String> = self
+            .regions
+            .iter()
+            .map(|region| {
+                format!(
+                    r#"{{"name":"{}","cat":"label","ph":"X","ts":{},"dur":{},"pid":1,"tid":1}}"#,
+                    escape_json(&region.label),
+                    region.start,
+                    region.duration
+                )
+            })
+            .collect();
+        format!(r#"{{"traceEvents":[{}]}}"#, events.join(","))
+    }
+}
+
+// Top REPORT_ROWS entries, most executed first
+fn hottest<K: Clone + Ord>(counts: &BTreeMap<K, u64>) -> Vec<(K, u64)> {
+    let mut entries: Vec<(K, u64)> = counts.iter().map(|(k, &v)| (k.clone(), v)).collect();
+    entries.sort_by(|(lk, lv), (rk, rv)| rv.cmp(lv).then(lk.cmp(rk)));
+    entries.truncate(REPORT_ROWS);
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::vm::{Step, VM};
+
+    // counts $0 up to 3
+    const LOOP: &str = "load $0 #0
+load $1 #1
+load $2 #3
+load $3 #16
+loop: add $0 $1 $0
+neq $0 $2
+jeq $3
+done: hlt
+";
+
+    fn profile() -> (VM, Profile) {
+        let mut vm = VM::new();
+        vm.load(assemble("loop.s", LOOP).unwrap()).unwrap();
+        vm.set_profiling(true);
+        assert_eq!(vm.run(), Ok(Step::Done));
+        let profile = vm.profile().unwrap().clone();
+        (vm, profile)
+    }
+
+    #[test]
+    fn test_profile_counts() {
+        let (_, profile) = profile();
+        assert_eq!(profile.total, 14);
+        assert_eq!(profile.opcodes["LOAD"], 4);
+        assert_eq!(profile.opcodes["ADD"], 3);
+        assert_eq!(profile.opcodes["HLT"], 1);
+        assert_eq!(profile.addresses[&16], 3);
+        assert_eq!(profile.addresses[&25], 1);
+    }
+
+    #[test]
+    fn test_profile_labels() {
+        let (vm, profile) = profile();
+        let labels = profile.labels(vm.debug_info.as_ref().unwrap());
+        assert_eq!(labels[NO_LABEL], 4);
+        assert_eq!(labels["loop"], 9);
+        assert_eq!(labels["done"], 1);
+    }
+
+    #[test]
+    fn test_profile_regions() {
+        let (_, profile) = profile();
+        let regions: Vec<(&str, u64, u64)> = profile
+            .regions
+            .iter()
+            .map(|region| (region.label.as_str(), region.start, region.duration))
+            .collect();
+        assert_eq!(
+            regions,
+            vec![(NO_LABEL, 0, 4), ("loop", 4, 9), ("done", 13, 1)]
+        );
+    }
+
+    #[test]
+    fn test_profile_folded() {
+        let (vm, profile) = profile();
+        assert_eq!(
+            profile.folded(&vm.program, vm.debug_info.as_ref()),
+            "loop.s;<no label>;0x0000 LOAD $0 #0 1
+loop.s;<no label>;0x0004 LOAD $1 #1 1
+loop.s;<no label>;0x0008 LOAD $2 #3 1
+loop.s;<no label>;0x000C LOAD $3 #16 1
+loop.s;loop;0x0010 ADD $0 $1 $0 3
+loop.s;loop;0x0014 NEQ $0 $2 3
+loop.s;loop;0x0017 JEQ $3 3
+loop.s;done;0x0019 HLT 1
+"
+        );
+    }
+
+    #[test]
+    fn test_profile_chrome_trace() {
+        let (_, profile) = profile();
+        assert_eq!(
+            profile.chrome_trace(),
+            concat!(
+                r#"{"traceEvents":["#,
+                r#"{"name":"<no label>","cat":"label","ph":"X","ts":0,"dur":4,"pid":1,"tid":1},"#,
+                r#"{"name":"loop","cat":"label","ph":"X","ts":4,"dur":9,"pid":1,"tid":1},"#,
+                r#"{"name":"done","cat":"label","ph":"X","ts":13,"dur":1,"pid":1,"tid":1}"#,
+                r#"]}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_profile_report() {
+        let (vm, profile) = profile();
+        let report = profile.report(&vm.program, vm.debug_info.as_ref());
+        assert_eq!(
+            report,
+            "total instructions: 14
+opcodes:
+  LOAD              4  28.57%
+  ADD               3  21.43%
+  JEQ               3  21.43%
+  NEQ               3  21.43%
+  HLT               1   7.14%
+addresses:
+  0x0010          3  21.43%  ADD $0 $1 $0     loop.s:5 (in `loop`)
+  0x0014          3  21.43%  NEQ $0 $2        loop.s:6 (in `loop`)
+  0x0017          3  21.43%  JEQ $3           loop.s:7 (in `loop`)
+  0x0000          1   7.14%  LOAD $0 #0       loop.s:1
+  0x0004          1   7.14%  LOAD $1 #1       loop.s:2
+  0x0008          1   7.14%  LOAD $2 #3       loop.s:3
+  0x000C          1   7.14%  LOAD $3 #16      loop.s:4
+  0x0019          1   7.14%  HLT              loop.s:8 (in `done`)
+labels:
+  loop                      9  64.29%
+  <no label>                4  28.57%
+  done                      1   7.14%
+"
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use crate::instructions::MNEMONICS;
+
+/// File in the user's home the interactive history is kept in
+const HISTORY_FILE: &str = ".toyvm_history";
+
+/// Where the REPL reads its lines from
+pub trait LineSource {
+    /// Shows `prompt` and reads the next line. `None` means end of input
+    fn read_line(&mut self, prompt: &str, output: &mut dyn Write) -> io::Result<Option<String>>;
+
+    /// Called with the labels defined so far, after every line
+    fn set_labels(&mut self, _labels: Vec<String>) {}
+}
+
+impl<R: BufRead> LineSource for R {
+    fn read_line(&mut self, prompt: &str, output: &mut dyn Write) -> io::Result<Option<String>> {
+        write!(output, "{}", prompt)?;
+        output.flush()?;
+        let mut line = String::new();
+        if BufRead::read_line(self, &mut line)? == 0 {
+            writeln!(output)?;
+            return Ok(None);
+        }
+        Ok(Some(line))
+    }
+}
+
+/// Completes dot-commands, mnemonics and `@label` operands
+struct Completion {
+    commands: &'static [&'static str],
+    labels: Vec<String>,
+}
+
+impl Completion {
+    // (start of the completed word, candidates)
+    fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let start = line[..pos]
+            .rfind(char::is_whitespace)
+            .map_or(0, |index| index + 1);
+        let word = &line[start..pos];
+        let first = line[..start].trim().is_empty();
+        let mut candidates: Vec<String> = if let Some(label) = word.strip_prefix('@') {
+            self.labels
+                .iter()
+                .filter(|name| name.starts_with(label))
+                .map(|name| format!("@{}", name))
+                .collect()
+        } else if first && word.starts_with('.') {
+            self.commands
+                .iter()
+                .filter(|command| command.starts_with(word))
+                .map(|command| command.to_string())
+                .collect()
+        } else if first {
+            // keep the case the user started typing in
+            let lowercase = !word.chars().any(char::is_uppercase);
+            MNEMONICS
+                .iter()
+                .filter(|mnemonic| mnemonic.starts_with(&word.to_uppercase()))
+                .map(|mnemonic| match lowercase {
+                    true => mnemonic.to_lowercase(),
+                    false => mnemonic.to_string(),
+                })
+                .collect()
+        } else {
+            vec![]
+        };
+        candidates.sort();
+        (start, candidates)
+    }
+}
+
+impl Completer for Completion {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(line, pos))
+    }
+}
+
+impl Hinter for Completion {
+    type Hint = String;
+}
+
+impl Highlighter for Completion {}
+
+impl Validator for Completion {}
+
+impl Helper for Completion {}
+
+/// Interactive terminal input with line editing, persistent history and tab completion
+pub struct Editor {
+    editor: rustyline::Editor<Completion, DefaultHistory>,
+    history: Option<PathBuf>,
+}
+
+impl Editor {
+    pub fn new(commands: &'static [&'static str]) -> Result<Self, String> {
+        let mut editor = rustyline::Editor::new().map_err(|err| err.to_string())?;
+        editor.set_helper(Some(Completion {
+            commands,
+            labels: vec![],
+        }));
+        let history = history_path();
+        if let Some(path) = &history {
+            // there's no history on the first run
+            let _ = editor.load_history(path);
+        }
+        Ok(Editor { editor, history })
+    }
+}
+
+impl LineSource for Editor {
+    fn read_line(&mut self, prompt: &str, _output: &mut dyn Write) -> io::Result<Option<String>> {
+        loop {
+            match self.editor.readline(prompt) {
+                Ok(line) => {
+                    if !line.trim().is_empty() {
+                        let _ = self.editor.add_history_entry(line.as_str());
+                    }
+                    return Ok(Some(line));
+                }
+                // Ctrl-C drops the line being edited
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => return Ok(None),
+                Err(ReadlineError::Io(err)) => return Err(err),
+                Err(err) => return Err(io::Error::other(err.to_string())),
+            }
+        }
+    }
+
+    fn set_labels(&mut self, labels: Vec<String>) {
+        if let Some(completion) = self.editor.helper_mut() {
+            completion.labels = labels;
+        }
+    }
+}
+
+impl Drop for Editor {
+    fn drop(&mut self) {
+        if let Some(path) = &self.history {
+            let _ = self.editor.save_history(path);
+        }
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instructions::Opcode;
+
+    fn completion() -> Completion {
+        Completion {
+            commands: &[".quit", ".regs", ".registers", ".run"],
+            labels: vec!["loop".to_string(), "done".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_complete_commands_and_mnemonics() {
+        let completion = completion();
+        assert_eq!(
+            completion.candidates(".r", 2),
+            (
+                0,
+                vec![
+                    ".registers".to_string(),
+                    ".regs".to_string(),
+                    ".run".to_string()
+                ]
+            )
+        );
+        assert_eq!(
+            completion.candidates("jm", 2),
+            (
+                0,
+                vec!["jmp".to_string(), "jmpb".to_string(), "jmpf".to_string()]
+            )
+        );
+        assert_eq!(
+            completion.candidates("  LO", 4),
+            (2, vec!["LOAD".to_string(), "LOADF".to_string()])
+        );
+        assert_eq!(completion.candidates("load $0 #1", 10), (8, vec![]));
+        for mnemonic in MNEMONICS {
+            assert_ne!(Opcode::from(*mnemonic), Opcode::IGL(0xFF), "{}", mnemonic);
+        }
+    }
+
+    #[test]
+    fn test_complete_labels() {
+        let completion = completion();
+        assert_eq!(
+            completion.candidates("jmp @l", 6),
+            (4, vec!["@loop".to_string()])
+        );
+        assert_eq!(completion.candidates("jmp @", 5).1.len(), 2);
+    }
+}
@@ -1,71 +1,608 @@
+use std::convert::TryFrom;
+use std::fs;
 use std::io;
-use std::io::Write;
+use std::io::{Stdout, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
 
+use crate::assembler::assemble_at;
+use crate::assembler::label_parsers::label_declaration_parser;
+use crate::assembler::operand_parsers::register_parser;
 use crate::assembler::program_parsers::program_parser;
-use crate::vm::VM;
+use crate::assembler::{line_of, unknown_opcode, Diagnostic, SymbolTable, Token};
+use crate::debug_info::DebugInfo;
+use crate::image::Image;
+use crate::instructions::{Opcode, OPCODES};
+use crate::observer::ConsoleObserver;
+use crate::vm::{Step, VM};
 
-#[derive(Default)]
-pub struct REPL {
+pub mod editor;
+
+use self::editor::{Editor, LineSource};
+
+/// File name reported in locations of instructions typed into the REPL
+const REPL_FILE: &str = "<repl>";
+
+const PROMPT: &str = ">>>>> ";
+/// Prompt for the lines of a multi-line block
+const BLOCK_PROMPT: &str = "..... ";
+
+/// Dot-commands offered by tab completion
+const COMMANDS: &[&str] = &[
+    ".quit",
+    ".history",
+    ".program",
+    ".registers",
+    ".regs",
+    ".flags",
+    ".reset",
+    ".clear",
+    ".set",
+    ".pc",
+    ".load",
+    ".save",
+    ".savebin",
+    ".snapshot",
+    ".restore",
+    ".run",
+    ".help",
+];
+
+/// Output shared by the REPL and its VM, so both end up in the same stream
+struct SharedWriter<W>(Arc<Mutex<W>>);
+
+impl<W> Clone for SharedWriter<W> {
+    fn clone(&self) -> Self {
+        SharedWriter(Arc::clone(&self.0))
+    }
+}
+
+impl<W: Write> Write for SharedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+pub struct REPL<R, W> {
     vm: VM,
 
     commands_buffer: Vec<String>,
+    /// Source of every accepted instruction line and loaded file
+    source: Vec<String>,
+    /// Block being entered: its first line in `commands_buffer` and lines so far
+    block: Option<(usize, Vec<String>)>,
+
+    input: R,
+    output: SharedWriter<W>,
 }
 
-impl REPL {
-    pub fn new() -> Self {
-        Self::default()
+impl REPL<Editor, Stdout> {
+    /// Interactive REPL on the terminal
+    pub fn new() -> Result<Self, String> {
+        Ok(REPL::with_io(Editor::new(COMMANDS)?, io::stdout()))
     }
+}
 
-    pub fn run(&mut self) {
-        println!("welcome");
-        loop {
-            let mut buffer = String::new();
-            let stdin = io::stdin();
-
-            print!(">>>>> ");
-            io::stdout().flush().expect("failed to flush stdout");
-
-            stdin.read_line(&mut buffer).expect("failed to read stdin");
-            self.commands_buffer.push(buffer.trim().to_string());
-            let buffer = buffer.trim();
-            match buffer {
-                ".quit" => {
-                    println!("exit");
-                    std::process::exit(0);
+impl<R: LineSource, W: Write + Send + 'static> REPL<R, W> {
+    pub fn with_io(input: R, output: W) -> Self {
+        let output = SharedWriter(Arc::new(Mutex::new(output)));
+        REPL {
+            vm: new_vm(&output),
+            commands_buffer: vec![],
+            source: vec![],
+            block: None,
+            input,
+            output,
+        }
+    }
+
+    /// Everything written by the REPL and the VM so far
+    pub fn output(&self) -> MutexGuard<'_, W> {
+        self.output.0.lock().unwrap()
+    }
+
+    // appends assembled code at the end of the program and its data at the end of memory,
+    // where `data_base` placed it
+    fn append(&mut self, image: Image) -> Result<(), String> {
+        self.vm.memory.extend(image.data);
+        let pc_offset = self.vm.program.len();
+        if let Some(debug_info) = &image.debug_info {
+            self.vm
+                .debug_info
+                .get_or_insert_with(|| DebugInfo::new(REPL_FILE))
+                .append(debug_info, pc_offset, 0);
+        }
+        for byte in image.code {
+            self.vm.add_byte(byte);
+        }
+        Ok(())
+    }
+
+    fn load(&mut self, path: &str) -> Result<(), String> {
+        let source = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        let (base, data_base) = (self.vm.program.len(), self.vm.memory.len());
+        let image = assemble_at(path, &source, base, data_base, &self.symbols())
+            .map_err(|err| err.to_string())?;
+        self.append(image)?;
+        self.source.push(source.trim_end().to_string());
+        Ok(())
+    }
+
+    fn save(&self, path: &str) -> Result<(), String> {
+        let mut source = self.source.join("\n");
+        source.push('\n');
+        fs::write(path, source).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    fn save_bin(&self, path: &str) -> Result<(), String> {
+        let image = Image {
+            code: self.vm.program.clone(),
+            // memory starts empty, so the data appended by the session keeps its addresses
+            data: self.vm.memory.clone(),
+            debug_info: self.vm.debug_info.clone(),
+        };
+        fs::write(path, image.to_bytes()).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    fn snapshot(&self, path: &str) -> Result<(), String> {
+        fs::write(path, self.vm.snapshot()).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    // the typed source is not part of a snapshot, `.save` starts over afterwards
+    fn restore(&mut self, path: &str) -> Result<(), String> {
+        let snapshot = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+        self.vm
+            .restore(&snapshot)
+            .map_err(|err| format!("{}: {:?}", path, err))?;
+        self.source.clear();
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        self.vm.program.clear();
+        self.vm.debug_info = None;
+        self.vm.set_pc(0);
+        self.source.clear();
+    }
+
+    // .set $3 42
+    fn set_register(&mut self, argument: &str) -> Result<(), String> {
+        let mut args = argument.split_whitespace();
+        let (register, value) = match (args.next(), args.next(), args.next()) {
+            (Some(register), Some(value), None) => (register, value),
+            _ => return Err("usage: .set $N <value>".to_string()),
+        };
+        let register = parse_register(register)?;
+        let value = parse_number(value)
+            .and_then(|value| i32::try_from(value).ok())
+            .ok_or_else(|| format!("invalid value `{}`", value))?;
+        self.vm.registers[register] = value;
+        Ok(())
+    }
+
+    // .pc 0x10
+    fn set_pc(&mut self, argument: &str) -> Result<(), String> {
+        let pc = parse_number(argument)
+            .and_then(|pc| usize::try_from(pc).ok())
+            .ok_or_else(|| format!("invalid address `{}`", argument))?;
+        self.vm.set_pc(pc);
+        Ok(())
+    }
+
+    fn flags(&self) -> String {
+        format!(
+            "pc        = {}\nequal     = {}\nremainder = {}",
+            self.vm.pc(),
+            self.vm.equal_flag(),
+            self.vm.remainder()
+        )
+    }
+
+    // opcode syntax and description, for every opcode or the named one
+    fn help(&self, mnemonic: &str) -> Result<String, String> {
+        let opcodes = match mnemonic {
+            "" => OPCODES.to_vec(),
+            _ => match Opcode::from(mnemonic) {
+                Opcode::IGL(_) => return Err(format!("unknown opcode `{}`", mnemonic)),
+                opcode => vec![opcode],
+            },
+        };
+        let width = opcodes
+            .iter()
+            .map(|op| op.syntax().len())
+            .max()
+            .unwrap_or(0);
+        let lines: Vec<String> = opcodes
+            .iter()
+            .map(|op| {
+                format!(
+                    "{:<width$}  {}",
+                    op.syntax(),
+                    op.description(),
+                    width = width
+                )
+            })
+            .collect();
+        Ok(lines.join("\n"))
+    }
+
+    // labels defined in the session
+    fn symbols(&self) -> SymbolTable {
+        self.vm
+            .debug_info
+            .iter()
+            .flat_map(|debug_info| &debug_info.symbols)
+            .map(|symbol| (symbol.name.clone(), symbol.offset))
+            .collect()
+    }
+
+    // assembles typed code, appends it and executes everything appended
+    fn enter(&mut self, source: &str, line_offset: usize) -> io::Result<Option<i32>> {
+        let (base, data_base) = (self.vm.program.len(), self.vm.memory.len());
+        let image = match program_parser(source) {
+            Ok((rest, mut program)) if rest.trim().is_empty() => {
+                program.offset_lines(line_offset);
+                program
+                    .to_image_at(REPL_FILE, base, data_base, &self.symbols())
+                    .map_err(|err| err.to_string())
+            }
+            Err(nom::Err::Failure((rest, _))) => {
+                let line = line_offset + line_of(source, rest);
+                Err(Diagnostic::new(REPL_FILE, line, unknown_opcode(rest)).to_string())
+            }
+            _ => Err("Unable to parse input".to_string()),
+        };
+        if let Err(err) = image.and_then(|image| self.append(image)) {
+            writeln!(self.output, "{}", err)?;
+            return Ok(None);
+        }
+        self.source.push(source.to_string());
+        while self.vm.pc() < self.vm.program.len() {
+            match self.vm.run_once() {
+                Ok(Step::Done) => break,
+                Ok(_) => (),
+                Err(e) => {
+                    let message = self.vm.error_message(e);
+                    writeln!(self.output, "exit {}: {}", e, message)?;
+                    return Ok(Some(1));
                 }
-                ".history" => {
-                    for cmd in &self.commands_buffer {
-                        println!("{}", cmd)
-                    }
+            }
+        }
+        Ok(None)
+    }
+
+    fn registers_table(&self, nonzero: bool) -> String {
+        let mut table = format!("{:<4} {:>10} {:>11}", "reg", "hex", "dec");
+        for (register, value) in self.vm.registers.iter().enumerate() {
+            if nonzero && *value == 0 {
+                continue;
+            }
+            table.push_str(&format!(
+                "\n{:<4} {:#010X} {:>11}",
+                format!("${}", register),
+                value,
+                value
+            ));
+        }
+        table
+    }
+
+    /// Runs the session until `.quit`, end of input or a VM error.
+    /// Returns the exit status: 0 on `.quit`/end of input, 1 if the VM failed
+    pub fn run(&mut self) -> i32 {
+        match self.session() {
+            Ok(status) => status,
+            Err(err) => {
+                eprintln!("repl: {}", err);
+                1
+            }
+        }
+    }
+
+    fn session(&mut self) -> io::Result<i32> {
+        writeln!(self.output, "welcome")?;
+        loop {
+            let prompt = match self.block {
+                Some(_) => BLOCK_PROMPT,
+                None => PROMPT,
+            };
+            let line = match self.input.read_line(prompt, &mut self.output)? {
+                Some(line) => line,
+                None => break Ok(0),
+            };
+            if let Some(status) = self.handle(&line)? {
+                break Ok(status);
+            }
+            self.input.set_labels(self.symbols().into_keys().collect());
+        }
+    }
+
+    /// Executes a single line. Returns the exit status once the session is over
+    pub fn handle(&mut self, line: &str) -> io::Result<Option<i32>> {
+        self.commands_buffer.push(line.trim().to_string());
+        if let Some((start, lines)) = &mut self.block {
+            if !line.trim().is_empty() {
+                lines.push(line.trim_end().to_string());
+                return Ok(None);
+            }
+            // a blank line ends the block
+            let source = lines.join("\n");
+            let start = *start;
+            self.block = None;
+            return self.enter(&source, start);
+        }
+        let buffer = line.trim();
+        let (command, argument) = match buffer.split_once(char::is_whitespace) {
+            Some((command, argument)) => (command, argument.trim()),
+            None => (buffer, ""),
+        };
+        let result = match (command, argument) {
+            (".quit", _) => {
+                writeln!(self.output, "exit")?;
+                return Ok(Some(0));
+            }
+            (".history", _) => {
+                for cmd in &self.commands_buffer {
+                    writeln!(self.output, "{}", cmd)?;
                 }
-                ".program" => {
-                    self.vm
-                        .program
-                        .iter()
-                        .for_each(|&instruction| println!("{:X?}", instruction));
+                Ok(())
+            }
+            (".program", _) => {
+                for instruction in &self.vm.program {
+                    writeln!(self.output, "{:X?}", instruction)?;
                 }
-                ".registers" => {
-                    println!("Listing registers and all contents:");
-                    println!("{:#?}", self.vm.registers);
-                    println!("End of Register Listing")
+                Ok(())
+            }
+            (".registers", "") | (".regs", "") => {
+                let table = self.registers_table(false);
+                writeln!(self.output, "{}", table)?;
+                Ok(())
+            }
+            (".registers", "nonzero") | (".regs", "nonzero") => {
+                let table = self.registers_table(true);
+                writeln!(self.output, "{}", table)?;
+                Ok(())
+            }
+            (".flags", _) => {
+                let flags = self.flags();
+                writeln!(self.output, "{}", flags)?;
+                Ok(())
+            }
+            (".reset", _) => {
+                self.vm = new_vm(&self.output);
+                self.source.clear();
+                Ok(())
+            }
+            (".clear", _) => {
+                self.clear();
+                Ok(())
+            }
+            (".help", mnemonic) => match self.help(mnemonic) {
+                Ok(help) => {
+                    writeln!(self.output, "{}", help)?;
+                    Ok(())
                 }
-                _ => match program_parser(buffer) {
-                    Ok((_, result)) => {
-                        let bytecode = result.to_bytes();
-                        for byte in bytecode {
-                            self.vm.add_byte(byte);
-                        }
-                        if let Err(e) = self.vm.run_once() {
-                            println!("exit {}", e);
-                            std::process::exit(1);
-                        }
+                Err(e) => Err(e),
+            },
+            (".set", argument) => self.set_register(argument),
+            (".pc", argument) => self.set_pc(argument),
+            (".load", path) if !path.is_empty() => self.load(path),
+            (".save", path) if !path.is_empty() => self.save(path),
+            (".savebin", path) if !path.is_empty() => self.save_bin(path),
+            (".snapshot", path) if !path.is_empty() => self.snapshot(path),
+            (".restore", path) if !path.is_empty() => self.restore(path),
+            (".run", _) => match self.vm.run() {
+                Err(e) => Err(format!("exit {}: {}", e, self.vm.error_message(e))),
+                Ok(_) => Ok(()),
+            },
+            // a line declaring a label starts a block, executed once it's complete
+            _ if label_declaration_parser(buffer).is_ok() => {
+                self.block = Some((self.commands_buffer.len() - 1, vec![buffer.to_string()]));
+                Ok(())
+            }
+            _ => return self.enter(buffer, self.commands_buffer.len() - 1),
+        };
+        if let Err(err) = result {
+            writeln!(self.output, "{}", err)?;
+        }
+        Ok(None)
+    }
+}
+
+fn new_vm<W: Write + Send + 'static>(output: &SharedWriter<W>) -> VM {
+    let mut vm = VM::new();
+    vm.clear_observers();
+    vm.add_observer(Box::new(ConsoleObserver::new(Box::new(output.clone()))));
+    vm.set_output(Box::new(output.clone()));
+    vm
+}
+
+// $N
+fn parse_register(input: &str) -> Result<usize, String> {
+    match register_parser(input) {
+        Ok(("", Token::Register(register))) => Ok(register as usize),
+        _ => Err(format!("invalid register `{}`", input)),
+    }
+}
+
+// decimal or 0x prefixed hex
+fn parse_number(input: &str) -> Option<i64> {
+    let (negative, digits) = match input.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, input),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse().ok()?,
+    };
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    #[test]
+    fn test_state_commands() {
+        let mut repl = REPL::with_io(io::empty(), vec![]);
+        repl.set_register("$3 42").unwrap();
+        repl.set_register("$4 -0x10").unwrap();
+        assert_eq!(repl.vm.registers[3], 42);
+        assert_eq!(repl.vm.registers[4], -16);
+        assert!(repl.set_register("$32 1").is_err());
+        assert!(repl.set_register("$1 4294967296").is_err());
+        assert!(repl.set_register("$1").is_err());
+
+        assert_eq!(
+            repl.registers_table(true),
+            "reg         hex         dec
+$3   0x0000002A          42
+$4   0xFFFFFFF0         -16"
+        );
+        assert_eq!(repl.registers_table(false).lines().count(), 33);
+        assert_eq!(repl.help("").unwrap().lines().count(), OPCODES.len());
+
+        repl.set_pc("0x10").unwrap();
+        assert!(repl.set_pc("-1").is_err());
+        assert_eq!(
+            repl.flags(),
+            "pc        = 16\nequal     = false\nremainder = 0"
+        );
+
+        repl.append(assemble(REPL_FILE, "hlt").unwrap()).unwrap();
+        repl.source.push("hlt".to_string());
+        repl.clear();
+        assert!(repl.vm.program.is_empty());
+        assert_eq!(repl.vm.pc(), 0);
+        assert!(repl.source.is_empty());
+        assert_eq!(repl.vm.registers[3], 42);
+    }
+
+    #[test]
+    fn test_load_save() {
+        let dir = std::env::temp_dir();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        fs::write(path("repl_load.s"), "load $1 #2\nadd $0 $1 $0\nhlt\n").unwrap();
+
+        let mut repl = REPL::with_io(io::empty(), vec![]);
+        repl.source.push("load $0 #40".to_string());
+        repl.append(assemble(REPL_FILE, "load $0 #40").unwrap())
+            .unwrap();
+        assert_eq!(repl.vm.run_once(), Ok(Step::Continue));
+        repl.load(&path("repl_load.s")).unwrap();
+        assert_eq!(repl.vm.run(), Ok(Step::Done));
+        assert_eq!(repl.vm.registers[0], 42);
+        assert_eq!(repl.vm.location(4), format!("{}:1", path("repl_load.s")));
+        assert!(repl.load(&path("repl_missing.s")).is_err());
+
+        repl.save(&path("repl_save.s")).unwrap();
+        assert_eq!(
+            fs::read_to_string(path("repl_save.s")).unwrap(),
+            "load $0 #40\nload $1 #2\nadd $0 $1 $0\nhlt\n"
+        );
+        repl.save_bin(&path("repl_save.bin")).unwrap();
+        let image = Image::from_bytes(&fs::read(path("repl_save.bin")).unwrap()).unwrap();
+        assert_eq!(image.code, repl.vm.program);
+        assert_eq!(image.debug_info, repl.vm.debug_info);
+    }
+
+    #[test]
+    fn test_load_data() {
+        let path = std::env::temp_dir().join("repl_hello.s");
+        let path = path.to_str().unwrap();
+        let source = "load $0 @greeting\nprts $0\nhlt\ngreeting: .asciiz 'Hello'\n";
+        fs::write(path, source).unwrap();
+
+        let mut repl = REPL::with_io(io::empty(), vec![]);
+        repl.handle("s: .asciiz 'Hi'").unwrap();
+        repl.handle("").unwrap();
+        repl.load(path).unwrap();
+        assert_eq!(repl.vm.memory(), b"Hi\0Hello\0");
+        assert_eq!(repl.vm.run(), Ok(Step::Done));
+        assert_eq!(repl.vm.registers[0], 3);
+        assert_eq!(
+            String::from_utf8(repl.output().clone()).unwrap(),
+            "HelloHLT encountered\n"
+        );
+
+        let bin = format!("{}.bin", path);
+        repl.save_bin(&bin).unwrap();
+        let image = Image::from_bytes(&fs::read(&bin).unwrap()).unwrap();
+        assert_eq!(image.data, b"Hi\0Hello\0");
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let path = std::env::temp_dir().join("repl.snapshot");
+        let path = path.to_str().unwrap();
+        let mut repl = REPL::with_io(io::empty(), vec![]);
+        repl.handle("load $0 #42").unwrap();
+        repl.snapshot(path).unwrap();
+        repl.handle(".reset").unwrap();
+        assert!(repl.vm.program.is_empty());
+
+        repl.restore(path).unwrap();
+        assert_eq!(repl.vm.registers[0], 42);
+        assert_eq!(repl.vm.pc(), 4);
+        assert_eq!(repl.vm.location(0), "<repl>:1");
+        assert!(repl.restore(&format!("{}.missing", path)).is_err());
+    }
+
+    fn session(input: &str) -> (i32, String) {
+        let mut repl = REPL::with_io(input.as_bytes(), vec![]);
+        let status = repl.run();
+        let output = String::from_utf8(repl.output().clone()).unwrap();
+        (status, output)
+    }
+
+    #[test]
+    fn test_exit_status() {
+        assert_eq!(
+            session(".quit\nhlt\n"),
+            (0, "welcome\n>>>>> exit\n".to_string())
+        );
+        assert_eq!(session(""), (0, "welcome\n>>>>> \n".to_string()));
+        let (status, output) = session("igl\n.quit\n");
+        assert_eq!(status, 1);
+        assert_eq!(
+            output,
+            "welcome\n>>>>> IGL 255 encountered at <repl>:1\nexit 2: illegal opcode at <repl>:1\n"
+        );
+    }
+
+    // Every transcript is a recorded session: lines starting with a prompt
+    // carry the input typed after it, everything else is expected output
+    #[test]
+    fn test_transcripts() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/src/repl/transcripts");
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let transcript = fs::read_to_string(&path).unwrap();
+            let mut input = String::new();
+            let mut expected = String::new();
+            for line in transcript.lines() {
+                let prompt = [PROMPT, BLOCK_PROMPT]
+                    .iter()
+                    .find(|prompt| line.starts_with(prompt.trim_end()));
+                match prompt {
+                    Some(prompt) => {
+                        input.push_str(line[prompt.len().min(line.len())..].trim_end());
+                        input.push('\n');
+                        expected.push_str(prompt);
                     }
-                    Err(_) => {
-                        println!("Unable to parse input");
-                        continue;
+                    None => {
+                        expected.push_str(line);
+                        expected.push('\n');
                     }
-                },
+                }
             }
+            let (status, output) = session(&input);
+            assert_eq!(status, 0, "{}", path.display());
+            assert_eq!(output, expected, "{}", path.display());
         }
     }
 }
@@ -0,0 +1,287 @@
+use std::io;
+use std::io::Write;
+
+use crate::vm::{Register, Value};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TraceFormat {
+    /// One JSON object per line
+    JsonLines,
+    /// Compact human readable columns
+    Text,
+}
+
+impl TraceFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "jsonl" | "json" => Some(TraceFormat::JsonLines),
+            "text" => Some(TraceFormat::Text),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct RegisterWrite {
+    pub register: Register,
+    pub old: Value,
+    pub new: Value,
+}
+
+impl RegisterWrite {
+    // `$fN` and `$vN` writes are told apart from `$N` ones by their key
+    fn to_json(&self) -> String {
+        let (key, index) = match self.register {
+            Register::Int(index) => ("register", index),
+            Register::Float(index) => ("float_register", index),
+            Register::Vector(index) => ("vector_register", index),
+        };
+        format!(
+            r#"{{"{}":{},"old":{},"new":{}}}"#,
+            key,
+            index,
+            value_json(&self.old),
+            value_json(&self.new)
+        )
+    }
+}
+
+// NaN and infinities have no JSON number, they are written as null
+fn value_json(value: &Value) -> String {
+    match value {
+        Value::Float(value) if !value.is_finite() => "null".to_string(),
+        Value::Vector(lanes) => json_array(lanes),
+        value => value.to_string(),
+    }
+}
+
+fn json_array<T: ToString>(items: &[T]) -> String {
+    let items: Vec<String> = items.iter().map(T::to_string).collect();
+    format!("[{}]", items.join(","))
+}
+
+/// Bytes stored at `address` by `VST` or `RDS`
+#[derive(Debug, PartialEq, Clone)]
+pub struct MemoryWrite {
+    pub address: usize,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+impl MemoryWrite {
+    fn to_json(&self) -> String {
+        format!(
+            r#"{{"address":{},"old":{},"new":{}}}"#,
+            self.address,
+            json_array(&self.old),
+            json_array(&self.new)
+        )
+    }
+
+    fn to_text(&self) -> String {
+        let hex = |bytes: &[u8]| {
+            let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            bytes.join(" ")
+        };
+        format!(
+            " mem[{:#06X}]: {} -> {}",
+            self.address,
+            hex(&self.old),
+            hex(&self.new)
+        )
+    }
+}
+
+/// Effects of a single executed instruction
+#[derive(Debug, PartialEq, Clone)]
+pub struct TraceRecord {
+    pub pc: usize,
+    pub instruction: String,
+    pub writes: Vec<RegisterWrite>,
+    pub memory: Vec<MemoryWrite>,
+    /// (old, new) if the instruction changed the flag
+    pub equal_flag: Option<(bool, bool)>,
+    /// (old, new) if the instruction changed the remainder
+    pub remainder: Option<(u32, u32)>,
+}
+
+impl TraceRecord {
+    pub fn to_json(&self) -> String {
+        let writes: Vec<String> = self.writes.iter().map(RegisterWrite::to_json).collect();
+        let mut json = format!(
+            r#"{{"pc":{},"instruction":"{}","writes":[{}]"#,
+            self.pc,
+            escape_json(&self.instruction),
+            writes.join(",")
+        );
+        if !self.memory.is_empty() {
+            let memory: Vec<String> = self.memory.iter().map(MemoryWrite::to_json).collect();
+            json.push_str(&format!(r#","memory":[{}]"#, memory.join(",")));
+        }
+        if let Some((old, new)) = self.equal_flag {
+            json.push_str(&format!(r#","equal_flag":{{"old":{},"new":{}}}"#, old, new));
+        }
+        if let Some((old, new)) = self.remainder {
+            json.push_str(&format!(r#","remainder":{{"old":{},"new":{}}}"#, old, new));
+        }
+        json.push('}');
+        json
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{:#06X} {:<16}", self.pc, self.instruction);
+        for write in &self.writes {
+            text.push_str(&format!(
+                " {}: {} -> {}",
+                write.register, write.old, write.new
+            ));
+        }
+        for write in &self.memory {
+            text.push_str(&write.to_text());
+        }
+        if let Some((old, new)) = self.equal_flag {
+            text.push_str(&format!(" eq: {} -> {}", old, new));
+        }
+        if let Some((old, new)) = self.remainder {
+            text.push_str(&format!(" rem: {} -> {}", old, new));
+        }
+        text.trim_end().to_string()
+    }
+}
+
+pub(crate) fn escape_json(input: &str) -> String {
+    let mut result = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Writes a record per executed instruction
+pub struct Tracer {
+    format: TraceFormat,
+    out: Box<dyn Write + Send>,
+}
+
+impl Tracer {
+    pub fn new(format: TraceFormat, out: Box<dyn Write + Send>) -> Self {
+        Tracer { format, out }
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let line = match self.format {
+            TraceFormat::JsonLines => record.to_json(),
+            TraceFormat::Text => record.to_text(),
+        };
+        writeln!(self.out, "{}", line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> TraceRecord {
+        TraceRecord {
+            pc: 8,
+            instruction: "DIV $1 $0 $2".to_string(),
+            writes: vec![RegisterWrite {
+                register: Register::Int(2),
+                old: Value::Int(0),
+                new: Value::Int(1),
+            }],
+            memory: vec![],
+            equal_flag: None,
+            remainder: Some((0, 1)),
+        }
+    }
+
+    #[test]
+    fn test_record_to_json() {
+        assert_eq!(
+            record().to_json(),
+            r#"{"pc":8,"instruction":"DIV $1 $0 $2","writes":[{"register":2,"old":0,"new":1}],"remainder":{"old":0,"new":1}}"#
+        );
+        let flag = TraceRecord {
+            pc: 0,
+            instruction: "EQ \"$0\"".to_string(),
+            writes: vec![],
+            memory: vec![],
+            equal_flag: Some((false, true)),
+            remainder: None,
+        };
+        assert_eq!(
+            flag.to_json(),
+            r#"{"pc":0,"instruction":"EQ \"$0\"","writes":[],"equal_flag":{"old":false,"new":true}}"#
+        );
+    }
+
+    #[test]
+    fn test_vector_and_memory_writes() {
+        let record = TraceRecord {
+            pc: 4,
+            instruction: "VST $v1 $0".to_string(),
+            writes: vec![RegisterWrite {
+                register: Register::Vector(1),
+                old: Value::Vector([0; 4]),
+                new: Value::Vector([1, -2, 3, 4]),
+            }],
+            memory: vec![MemoryWrite {
+                address: 16,
+                old: vec![0, 0],
+                new: vec![1, 255],
+            }],
+            equal_flag: None,
+            remainder: None,
+        };
+        assert_eq!(
+            record.to_json(),
+            r#"{"pc":4,"instruction":"VST $v1 $0","writes":[{"vector_register":1,"old":[0,0,0,0],"new":[1,-2,3,4]}],"memory":[{"address":16,"old":[0,0],"new":[1,255]}]}"#
+        );
+        assert_eq!(
+            record.to_text(),
+            "0x0004 VST $v1 $0       $v1: [0, 0, 0, 0] -> [1, -2, 3, 4] mem[0x0010]: 00 00 -> 01 FF"
+        );
+    }
+
+    #[test]
+    fn test_float_write() {
+        let record = TraceRecord {
+            pc: 0,
+            instruction: "FDIV $f0 $f1 $f2".to_string(),
+            writes: vec![RegisterWrite {
+                register: Register::Float(2),
+                old: Value::Float(0.0),
+                new: Value::Float(f64::INFINITY),
+            }],
+            memory: vec![],
+            equal_flag: None,
+            remainder: None,
+        };
+        assert_eq!(
+            record.to_json(),
+            r#"{"pc":0,"instruction":"FDIV $f0 $f1 $f2","writes":[{"float_register":2,"old":0,"new":null}]}"#
+        );
+        assert_eq!(record.to_text(), "0x0000 FDIV $f0 $f1 $f2 $f2: 0 -> inf");
+    }
+
+    #[test]
+    fn test_record_to_text() {
+        assert_eq!(
+            record().to_text(),
+            "0x0008 DIV $1 $0 $2     $2: 0 -> 1 rem: 0 -> 1"
+        );
+    }
+
+    #[test]
+    fn test_trace_format_parse() {
+        assert_eq!(TraceFormat::parse("jsonl"), Some(TraceFormat::JsonLines));
+        assert_eq!(TraceFormat::parse("text"), Some(TraceFormat::Text));
+        assert_eq!(TraceFormat::parse("xml"), None);
+    }
+}
@@ -1,24 +1,445 @@
-use crate::instructions::Opcode;
+use crate::debug_info::DebugInfo;
+use crate::disassembler::disassemble_one;
+use crate::image::{put_bytes, put_u32, put_u64, Image, ImageError, Reader};
+use crate::instructions::{Instruction, Opcode, Operand, OPCODES};
+use crate::observer::{ConsoleObserver, Observer};
+use crate::profiler::Profile;
+use crate::trace::{MemoryWrite, RegisterWrite, TraceRecord, Tracer};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Write};
 use std::result::Result;
 
+pub const REGISTER_COUNT: usize = 32;
+pub const FLOAT_REGISTER_COUNT: usize = 32;
+pub const VECTOR_REGISTER_COUNT: usize = 16;
+/// i32 lanes of a vector register
+pub const LANES: usize = 4;
+
+/// Content of a `$vN` register
+pub type Vector = [i32; LANES];
+
+/// Registers holding the arguments of a host call
+pub const SYSCALL_ARGS: [usize; 3] = [1, 2, 3];
+/// Register receiving the result of a host call
+pub const SYSCALL_RESULT: usize = 0;
+
+/// Host function run by `SYS #n`. It reads its arguments from the `SYSCALL_ARGS`
+/// registers; the returned value is written to `SYSCALL_RESULT` and an error fails
+/// the program with error 9
+pub type Syscall = Box<dyn FnMut(&mut VM) -> Result<i32, String> + Send>;
+
+/// Largest status `EXIT` accepts, larger ones are reserved for faults and host errors
+pub const MAX_EXIT_STATUS: i32 = 99;
+
+/// Registers `set_args` passes the number and the address of the argument table in
+pub const ARGC: usize = 0;
+pub const ARGV: usize = 1;
+/// Registers `set_args` passes the number and the address of the environment table in
+pub const ENVC: usize = 2;
+pub const ENVP: usize = 3;
+
+/// Instructions `step_back` can undo unless set otherwise with `set_history_limit`
+pub const DEFAULT_HISTORY_LIMIT: usize = 100_000;
+
+/// Magic bytes every VM snapshot starts with
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"TOYS";
+/// Current version of the snapshot layout
+pub const SNAPSHOT_VERSION: u8 = 5;
+
+/// Hard caps on a single VM instance. `None` means unlimited
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Limits {
+    /// Instructions executed before `run_once` fails with error 3
+    pub max_instructions: Option<u64>,
+    /// Bytes `ALOC` may allocate in total, exceeding it is error 4
+    pub max_heap_bytes: Option<usize>,
+    /// Nested `CALL`s, exceeding it is error 5
+    pub max_stack_depth: Option<usize>,
+    /// Largest program `load` accepts, exceeding it is error 6
+    pub max_program_size: Option<usize>,
+}
+
+/// Price of every opcode charged by the VM, its `Opcode::default_cost` unless set otherwise
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostTable([u64; 256]);
+
+impl Default for CostTable {
+    fn default() -> Self {
+        let mut costs = [Opcode::IGL(0xFF).default_cost(); 256];
+        for opcode in OPCODES {
+            costs[u8::from(opcode) as usize] = opcode.default_cost();
+        }
+        CostTable(costs)
+    }
+}
+
+impl CostTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(mut self, opcode: &Opcode, cost: u64) -> Self {
+        self.0[u8::from(opcode) as usize] = cost;
+        self
+    }
+
+    /// Cost of the instruction starting with `byte`. Illegal bytes cost as much as IGL
+    pub fn cost(&self, byte: u8) -> u64 {
+        match Opcode::from(byte) {
+            Opcode::IGL(_) => self.0[u8::from(&Opcode::IGL(byte)) as usize],
+            _ => self.0[byte as usize],
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct VM {
     /// Array of `hardware` registers
-    pub registers: [i32; 32],
+    pub(crate) registers: [i32; REGISTER_COUNT],
+    /// `$fN` registers of the floating point instructions
+    pub(crate) float_registers: [f64; FLOAT_REGISTER_COUNT],
+    /// `$vN` registers of the vector instructions
+    pub(crate) vector_registers: [Vector; VECTOR_REGISTER_COUNT],
     /// Program counter
     pc: usize,
     /// The bytecode of the program being run
-    pub program: Vec<u8>,
+    pub(crate) program: Vec<u8>,
+    /// Byte addressable memory, zeroed on creation
+    pub(crate) memory: Vec<u8>,
+    limits: Limits,
+    /// Number of instructions executed so far
+    executed: u64,
+    /// Bytes allocated by `ALOC` so far
+    allocated: usize,
+    /// Return addresses of the active `CALL`s
+    call_stack: Vec<usize>,
+    costs: CostTable,
+    /// Total cost of the instructions executed so far
+    consumed: u64,
+    syscalls: BTreeMap<u16, Syscall>,
     /// Remainder of modulo division ops
     remainder: u32,
     /// Last comparison result
     equal_flag: bool,
+    /// Status of the last `HLT` or `EXIT`
+    exit_code: i32,
+    /// Address of the instruction being executed
+    instruction_pc: usize,
+    /// Line table and symbols of the loaded program
+    pub(crate) debug_info: Option<DebugInfo>,
+    watchpoints: Vec<Watchpoint>,
+    /// Watchpoint triggered by the instruction being executed
+    watch_hit: Option<WatchHit>,
+    /// Keep an undo log of every executed instruction
+    recording: bool,
+    undo_log: UndoLog,
+    tracer: Option<Tracer>,
+    /// Registers written by the instruction being traced
+    trace_writes: Vec<RegisterWrite>,
+    /// Memory stored by the instruction being traced
+    trace_memory: Vec<MemoryWrite>,
+    profile: Option<Profile>,
+    observers: Observers,
+    console: Console,
+}
+
+// a VM can be moved to another thread, so hosts can run programs on a worker pool
+const _: fn() = || {
+    fn assert_send<T: Send>() {}
+    assert_send::<VM>();
+};
+
+/// Streams of the console I/O instructions, stdin and stdout unless replaced
+struct Console {
+    input: Box<dyn BufRead + Send>,
+    output: Box<dyn Write + Send>,
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Console {
+            input: Box::new(io::BufReader::new(io::stdin())),
+            output: Box::new(io::stdout()),
+        }
+    }
+}
+
+/// Observers notified of execution events, a console observer unless replaced
+struct Observers(Vec<Box<dyn Observer>>);
+
+impl Default for Observers {
+    fn default() -> Self {
+        Observers(vec![Box::new(ConsoleObserver::default())])
+    }
+}
+
+/// Undo entries of the most recent instructions, the oldest ones are dropped past `limit`
+struct UndoLog {
+    entries: VecDeque<UndoEntry>,
+    limit: usize,
+}
+
+impl Default for UndoLog {
+    fn default() -> Self {
+        UndoLog {
+            entries: VecDeque::new(),
+            limit: DEFAULT_HISTORY_LIMIT,
+        }
+    }
+}
+
+impl UndoLog {
+    fn push(&mut self, entry: UndoEntry) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.entries.len() == self.limit {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.entries.len() > limit {
+            self.entries.pop_front();
+        }
+    }
+}
+
+/// State overwritten by a single instruction
+#[derive(Debug, PartialEq)]
+struct UndoEntry {
+    pc: usize,
+    equal_flag: bool,
+    remainder: u32,
+    consumed: u64,
+    exit_code: i32,
+    /// (register, old value)
+    registers: Vec<(usize, i32)>,
+    /// (float register, old value)
+    float_registers: Vec<(usize, f64)>,
+    /// (vector register, old value)
+    vector_registers: Vec<(usize, Vector)>,
+    memory_len: usize,
+    allocated: usize,
+    stack_len: usize,
+    /// Return address a `RET` may pop
+    stack_top: Option<usize>,
+    /// (address, old byte)
+    memory: Vec<(usize, u8)>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Step {
     Done,
     Continue,
+    /// The instruction wrote to a watched register or memory range
+    Watchpoint(WatchHit),
+    /// `run_with_budget` can't afford the next instruction, which is left unexecuted
+    BudgetExhausted,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WatchKind {
+    /// Stop on every write, even if the value stays the same
+    Write,
+    /// Stop only when the value changes
+    Change,
+}
+
+/// A register of one of the banks, displayed as in assembly
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Register {
+    /// `$N`
+    Int(usize),
+    /// `$fN`
+    Float(usize),
+    /// `$vN`
+    Vector(usize),
+}
+
+impl Register {
+    /// Parses `$N`, `$fN` or `$vN`. `None` past the last register of the bank
+    pub fn parse(name: &str) -> Option<Register> {
+        let name = name.strip_prefix('$')?;
+        let register = if let Some(index) = name.strip_prefix('f') {
+            Register::Float(index.parse().ok()?)
+        } else if let Some(index) = name.strip_prefix('v') {
+            Register::Vector(index.parse().ok()?)
+        } else {
+            Register::Int(name.parse().ok()?)
+        };
+        match register {
+            Register::Int(index) if index < REGISTER_COUNT => Some(register),
+            Register::Float(index) if index < FLOAT_REGISTER_COUNT => Some(register),
+            Register::Vector(index) if index < VECTOR_REGISTER_COUNT => Some(register),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::Int(index) => write!(f, "${}", index),
+            Register::Float(index) => write!(f, "$f{}", index),
+            Register::Vector(index) => write!(f, "$v{}", index),
+        }
+    }
+}
+
+/// Content of a register
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Value {
+    Int(i32),
+    Float(f64),
+    Vector(Vector),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Float(value) => write!(f, "{}", value),
+            Value::Vector(lanes) => write!(f, "{:?}", lanes),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Watchpoint {
+    Register {
+        register: Register,
+        kind: WatchKind,
+    },
+    /// `len` bytes of memory from `start`
+    Memory {
+        start: usize,
+        len: usize,
+        kind: WatchKind,
+    },
+}
+
+/// A write a watchpoint stopped on. `pc` is the address of the writing instruction
+#[derive(Debug, PartialEq, Clone)]
+pub enum WatchHit {
+    Register {
+        register: Register,
+        old: Value,
+        new: Value,
+        pc: usize,
+    },
+    /// The watched bytes a store overwrote, from `address` on
+    Memory {
+        address: usize,
+        old: Vec<u8>,
+        new: Vec<u8>,
+        pc: usize,
+    },
+}
+
+impl WatchHit {
+    pub fn pc(&self) -> usize {
+        match self {
+            WatchHit::Register { pc, .. } | WatchHit::Memory { pc, .. } => *pc,
+        }
+    }
+}
+
+/// `$0: 1 -> 2` or `mem[0x0010]: 00 00 -> 01 FF`
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchHit::Register {
+                register, old, new, ..
+            } => write!(f, "{}: {} -> {}", register, old, new),
+            WatchHit::Memory {
+                address, old, new, ..
+            } => {
+                let hex = |bytes: &[u8]| {
+                    let bytes: Vec<String> =
+                        bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+                    bytes.join(" ")
+                };
+                write!(f, "mem[{:#06X}]: {} -> {}", address, hex(old), hex(new))
+            }
+        }
+    }
+}
+
+/// Configures a VM before creating it, see `VM::builder`
+pub struct VMBuilder {
+    memory_size: usize,
+    limits: Limits,
+    costs: CostTable,
+    console: bool,
+    observers: Vec<Box<dyn Observer>>,
+    input: Option<Box<dyn BufRead + Send>>,
+    output: Option<Box<dyn Write + Send>>,
+}
+
+impl VMBuilder {
+    /// Bytes of zeroed memory available to the program
+    pub fn memory_size(mut self, bytes: usize) -> Self {
+        self.memory_size = bytes;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Prices charged per opcode, see `VM::run_with_budget`
+    pub fn costs(mut self, costs: CostTable) -> Self {
+        self.costs = costs;
+        self
+    }
+
+    /// Adds an observer, notified in the order of addition
+    pub fn observer(mut self, observer: Box<dyn Observer>) -> Self {
+        self.observers.push(observer);
+        self
+    }
+
+    /// Leaves out the console observer printing `HLT encountered` and errors to stdout
+    pub fn quiet(mut self) -> Self {
+        self.console = false;
+        self
+    }
+
+    /// Stream read by `RDI` and `RDS` instead of stdin
+    pub fn input(mut self, input: Box<dyn BufRead + Send>) -> Self {
+        self.input = Some(input);
+        self
+    }
+
+    /// Stream written by `PRTI`, `PRTC` and `PRTS` instead of stdout
+    pub fn output(mut self, output: Box<dyn Write + Send>) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    pub fn build(self) -> VM {
+        let mut vm = VM::new();
+        vm.memory = vec![0; self.memory_size];
+        vm.limits = self.limits;
+        vm.costs = self.costs;
+        if !self.console {
+            vm.clear_observers();
+        }
+        vm.observers.0.extend(self.observers);
+        if let Some(input) = self.input {
+            vm.set_input(input);
+        }
+        if let Some(output) = self.output {
+            vm.set_output(output);
+        }
+        vm
+    }
 }
 
 impl VM {
@@ -26,161 +447,1109 @@ impl VM {
         Self::default()
     }
 
+    /// Starts configuring a VM with memory, limits and observers.
+    ///
+    /// ```
+    /// use toyvm::{Assembler, Limits, Step, VM};
+    ///
+    /// let mut vm = VM::builder()
+    ///     .memory_size(1024)
+    ///     .limits(Limits {
+    ///         max_instructions: Some(100),
+    ///         ..Limits::default()
+    ///     })
+    ///     .quiet()
+    ///     .build();
+    /// vm.load(Assembler::new().assemble("load $0 #42\nhlt\n").unwrap())
+    ///     .unwrap();
+    /// assert_eq!(vm.run(), Ok(Step::Done));
+    /// assert_eq!(vm.registers()[0], 42);
+    /// ```
+    pub fn builder() -> VMBuilder {
+        VMBuilder {
+            memory_size: 0,
+            limits: Limits::default(),
+            costs: CostTable::default(),
+            console: true,
+            observers: vec![],
+            input: None,
+            output: None,
+        }
+    }
+
     pub fn add_byte(&mut self, byte: u8) {
         self.program.push(byte)
     }
 
-    pub fn run(&mut self) -> Result<(), u8> {
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn set_pc(&mut self, pc: usize) {
+        self.pc = pc;
+    }
+
+    pub fn equal_flag(&self) -> bool {
+        self.equal_flag
+    }
+
+    pub fn remainder(&self) -> u32 {
+        self.remainder
+    }
+
+    /// Status the program stopped with: the register of `EXIT $r`, 0 for `HLT`
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code
+    }
+
+    pub fn registers(&self) -> &[i32; REGISTER_COUNT] {
+        &self.registers
+    }
+
+    pub fn float_registers(&self) -> &[f64; FLOAT_REGISTER_COUNT] {
+        &self.float_registers
+    }
+
+    pub fn vector_registers(&self) -> &[Vector; VECTOR_REGISTER_COUNT] {
+        &self.vector_registers
+    }
+
+    pub fn register_value(&self, register: Register) -> Value {
+        match register {
+            Register::Int(index) => Value::Int(self.registers[index]),
+            Register::Float(index) => Value::Float(self.float_registers[index]),
+            Register::Vector(index) => Value::Vector(self.vector_registers[index]),
+        }
+    }
+
+    pub fn program(&self) -> &[u8] {
+        &self.program
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Number of instructions executed so far
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    /// Total cost of the instructions executed so far, see `CostTable`
+    pub fn consumed(&self) -> u64 {
+        self.consumed
+    }
+
+    /// Bytes allocated by `ALOC` so far
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    /// Return addresses of the active `CALL`s, innermost last
+    pub fn call_stack(&self) -> &[usize] {
+        &self.call_stack
+    }
+
+    pub fn set_input(&mut self, input: Box<dyn BufRead + Send>) {
+        self.console.input = input;
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write + Send>) {
+        self.console.output = output;
+    }
+
+    /// Replaces the program with `image` and starts over from its first instruction, with
+    /// an empty call stack and undo log and fresh instruction, cost and heap quota counters.
+    /// Registers, flags and memory are kept; the data section is copied to the start of
+    /// memory, which grows to fit it.
+    /// Fails with error 6 if code and data are larger than allowed by the limits
+    pub fn load(&mut self, image: Image) -> Result<(), u8> {
+        if let Some(max) = self.limits.max_program_size {
+            let size = image.code.len() + image.data.len();
+            if size > max {
+                let message = format!(
+                    "program of {} bytes exceeds the quota of {} bytes",
+                    size, max
+                );
+                self.notify(|observer, vm| observer.on_error(vm, 6, &message));
+                return Err(6);
+            }
+        }
+        if self.memory.len() < image.data.len() {
+            self.memory.resize(image.data.len(), 0);
+        }
+        self.memory[..image.data.len()].copy_from_slice(&image.data);
+        self.program = image.code;
+        self.debug_info = image.debug_info;
+        self.pc = 0;
+        self.exit_code = 0;
+        self.call_stack.clear();
+        self.undo_log.entries.clear();
+        self.executed = 0;
+        self.consumed = 0;
+        self.allocated = 0;
+        Ok(())
+    }
+
+    /// Passes command-line arguments and `NAME=value` environment entries to the program.
+    /// Each list is appended to memory as a table of big-endian 32-bit addresses of
+    /// NUL-terminated strings, readable with `LDW`. Counts and table addresses go to
+    /// `ARGC`/`ARGV` and `ENVC`/`ENVP`
+    pub fn set_args<S: AsRef<str>>(&mut self, args: &[S], env: &[S]) {
+        let (argc, argv) = self.push_strings(args);
+        let (envc, envp) = self.push_strings(env);
+        self.registers[ARGC] = argc;
+        self.registers[ARGV] = argv;
+        self.registers[ENVC] = envc;
+        self.registers[ENVP] = envp;
+    }
+
+    // (count, table address)
+    fn push_strings<S: AsRef<str>>(&mut self, strings: &[S]) -> (i32, i32) {
+        let table = self.memory.len();
+        let mut address = table + 4 * strings.len();
+        for string in strings {
+            put_u32(&mut self.memory, address as u32);
+            address += string.as_ref().len() + 1;
+        }
+        for string in strings {
+            self.memory.extend_from_slice(string.as_ref().as_bytes());
+            self.memory.push(0);
+        }
+        (strings.len() as i32, table as i32)
+    }
+
+    /// Serialises the complete machine state: registers, pc, flags, memory
+    /// and the program with its debug info. Observers, limits, watchpoints
+    /// and the undo log are configuration and are not included
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut out = SNAPSHOT_MAGIC.to_vec();
+        out.push(SNAPSHOT_VERSION);
+        put_u32(&mut out, self.pc as u32);
+        out.push(self.equal_flag as u8);
+        put_u32(&mut out, self.remainder);
+        put_u64(&mut out, self.executed);
+        put_u64(&mut out, self.consumed);
+        put_u32(&mut out, self.exit_code as u32);
+        for register in self.registers.iter() {
+            put_u32(&mut out, *register as u32);
+        }
+        for register in self.float_registers.iter() {
+            put_u64(&mut out, register.to_bits());
+        }
+        for lane in self.vector_registers.iter().flatten() {
+            put_u32(&mut out, *lane as u32);
+        }
+        put_bytes(&mut out, &self.memory);
+        put_u64(&mut out, self.allocated as u64);
+        put_u32(&mut out, self.call_stack.len() as u32);
+        for address in &self.call_stack {
+            put_u32(&mut out, *address as u32);
+        }
+        let image = Image {
+            code: self.program.clone(),
+            data: vec![],
+            debug_info: self.debug_info.clone(),
+        };
+        put_bytes(&mut out, &image.to_bytes());
+        out
+    }
+
+    /// Replaces the machine state with a `snapshot`. The VM is left untouched on error
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), ImageError> {
+        let mut reader = Reader::new(snapshot);
+        if reader.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+        match reader.u8()? {
+            SNAPSHOT_VERSION => (),
+            version => return Err(ImageError::UnsupportedVersion(version)),
+        }
+        let pc = reader.u32()? as usize;
+        let equal_flag = reader.u8()? != 0;
+        let remainder = reader.u32()?;
+        let executed = reader.u64()?;
+        let consumed = reader.u64()?;
+        let exit_code = reader.u32()? as i32;
+        let mut registers = [0; REGISTER_COUNT];
+        for register in registers.iter_mut() {
+            *register = reader.u32()? as i32;
+        }
+        let mut float_registers = [0.0; FLOAT_REGISTER_COUNT];
+        for register in float_registers.iter_mut() {
+            *register = f64::from_bits(reader.u64()?);
+        }
+        let mut vector_registers = [[0; LANES]; VECTOR_REGISTER_COUNT];
+        for lane in vector_registers.iter_mut().flatten() {
+            *lane = reader.u32()? as i32;
+        }
+        let memory = reader.bytes()?.to_vec();
+        let allocated = reader.u64()? as usize;
+        let mut call_stack = vec![];
+        for _ in 0..reader.u32()? {
+            call_stack.push(reader.u32()? as usize);
+        }
+        let image = Image::from_bytes(reader.bytes()?)?;
+
+        // the snapshot comes from the host, quotas apply to programs
+        self.program = image.code;
+        self.debug_info = image.debug_info;
+        self.pc = pc;
+        self.instruction_pc = pc;
+        self.equal_flag = equal_flag;
+        self.remainder = remainder;
+        self.executed = executed;
+        self.consumed = consumed;
+        self.exit_code = exit_code;
+        self.registers = registers;
+        self.float_registers = float_registers;
+        self.vector_registers = vector_registers;
+        self.memory = memory;
+        self.allocated = allocated;
+        self.call_stack = call_stack;
+        self.undo_log.entries.clear();
+        Ok(())
+    }
+
+    /// Formats `pc` as a source location if debug info is available
+    pub fn location(&self, pc: usize) -> String {
+        self.debug_info
+            .as_ref()
+            .and_then(|debug_info| debug_info.location(pc))
+            .unwrap_or_else(|| format!("pc {}", pc))
+    }
+
+    /// Describes an error code returned by `run_once`
+    pub fn error_message(&self, code: u8) -> String {
+        let message = match code {
+            1 => "pc overflow",
+            2 => "illegal opcode",
+            3 => "instruction limit exceeded",
+            4 => "heap quota exceeded",
+            5 => "stack quota exceeded",
+            6 => "program size quota exceeded",
+            7 => "return with an empty call stack",
+            8 => "unregistered syscall",
+            9 => "syscall failed",
+            10 => "memory access out of bounds",
+            11 => "invalid input",
+            12 => "console I/O failed",
+            13 => "invalid register",
+            14 => "division fault",
+            15 => "exit status out of range",
+            16 => "truncated instruction",
+            17 => "jump before the start of the program",
+            _ => "unknown error",
+        };
+        format!("{} at {}", message, self.location(self.instruction_pc))
+    }
+
+    pub fn add_watchpoint(&mut self, register: Register, kind: WatchKind) {
+        self.remove_watchpoint(register);
+        self.watchpoints
+            .push(Watchpoint::Register { register, kind });
+    }
+
+    pub fn remove_watchpoint(&mut self, register: Register) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|watch| match watch {
+            Watchpoint::Register {
+                register: watched, ..
+            } => *watched != register,
+            Watchpoint::Memory { .. } => true,
+        });
+        before != self.watchpoints.len()
+    }
+
+    /// Watches stores to the `len` bytes from `start`, replacing a watchpoint at `start`
+    pub fn add_memory_watchpoint(&mut self, start: usize, len: usize, kind: WatchKind) {
+        self.remove_memory_watchpoint(start);
+        self.watchpoints
+            .push(Watchpoint::Memory { start, len, kind });
+    }
+
+    pub fn remove_memory_watchpoint(&mut self, start: usize) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|watch| match watch {
+            Watchpoint::Memory { start: watched, .. } => *watched != start,
+            Watchpoint::Register { .. } => true,
+        });
+        before != self.watchpoints.len()
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Makes `SYS #number` call `syscall`, replacing a previously registered function.
+    ///
+    /// ```
+    /// use toyvm::vm::{SYSCALL_ARGS, SYSCALL_RESULT};
+    /// use toyvm::{Assembler, Step, VM};
+    ///
+    /// let mut vm = VM::builder().quiet().build();
+    /// vm.register_syscall(1, |vm| Ok(vm.registers()[SYSCALL_ARGS[0]] * 2));
+    /// vm.load(Assembler::new().assemble("load $1 #21\nsys #1\nhlt\n").unwrap())
+    ///     .unwrap();
+    /// assert_eq!(vm.run(), Ok(Step::Done));
+    /// assert_eq!(vm.registers()[SYSCALL_RESULT], 42);
+    /// ```
+    pub fn register_syscall<F>(&mut self, number: u16, syscall: F)
+    where
+        F: FnMut(&mut VM) -> Result<i32, String> + Send + 'static,
+    {
+        self.syscalls.insert(number, Box::new(syscall));
+    }
+
+    /// Adds an observer notified after the already installed ones
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observers.0.push(observer);
+    }
+
+    /// Removes every observer, including the default console one
+    pub fn clear_observers(&mut self) {
+        self.observers.0.clear();
+    }
+
+    // observers are detached while notified so that they can inspect the VM
+    fn notify(&mut self, mut event: impl FnMut(&mut dyn Observer, &VM)) {
+        let mut observers = std::mem::replace(&mut self.observers, Observers(vec![]));
+        for observer in observers.0.iter_mut() {
+            event(observer.as_mut(), self);
+        }
+        self.observers = observers;
+    }
+
+    /// Emits a trace record for every executed instruction, `None` disables tracing.
+    /// Tracing stops if the tracer fails to write a record
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn is_tracing(&self) -> bool {
+        self.tracer.is_some()
+    }
+
+    /// Starts collecting execution counts, disabling drops the collected profile
+    pub fn set_profiling(&mut self, profiling: bool) {
+        self.profile = if profiling {
+            Some(Profile::new())
+        } else {
+            None
+        };
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// Enables the undo log used by `step_back`. Disabling it drops the recorded history
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+        if !recording {
+            self.undo_log.entries.clear();
+        }
+    }
+
+    /// Caps the undo log to the `limit` most recent instructions, dropping older ones
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.undo_log.set_limit(limit);
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recording
+    }
+
+    /// Number of instructions that can be undone
+    pub fn history_len(&self) -> usize {
+        self.undo_log.entries.len()
+    }
+
+    /// Reverts the last recorded instruction. Returns false if there is nothing to undo
+    pub fn step_back(&mut self) -> bool {
+        match self.undo_log.entries.pop_back() {
+            Some(entry) => {
+                for (register, old) in entry.registers.into_iter().rev() {
+                    self.registers[register] = old;
+                }
+                for (register, old) in entry.float_registers.into_iter().rev() {
+                    self.float_registers[register] = old;
+                }
+                for (register, old) in entry.vector_registers.into_iter().rev() {
+                    self.vector_registers[register] = old;
+                }
+                for (address, old) in entry.memory.into_iter().rev() {
+                    self.memory[address] = old;
+                }
+                self.pc = entry.pc;
+                self.instruction_pc = entry.pc;
+                self.equal_flag = entry.equal_flag;
+                self.remainder = entry.remainder;
+                self.consumed = entry.consumed;
+                self.exit_code = entry.exit_code;
+                self.memory.truncate(entry.memory_len);
+                self.allocated = entry.allocated;
+                self.call_stack.truncate(entry.stack_len);
+                // undo a RET
+                if self.call_stack.len() < entry.stack_len {
+                    self.call_stack.extend(entry.stack_top);
+                }
+                self.executed -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Runs until HLT or a triggered watchpoint, which is returned as `Step::Watchpoint`.
+    /// Calling `run` again resumes execution after the writing instruction
+    pub fn run(&mut self) -> Result<Step, u8> {
+        loop {
+            match self.run_once() {
+                Err(err) => break Err(err),
+                Ok(Step::Continue) => (),
+                Ok(step) => break Ok(step),
+            }
+        }
+    }
+
+    /// Runs like `run`, but stops with `Step::BudgetExhausted` before an instruction
+    /// costing more than what is left of `budget`. Calling it again resumes from there.
+    /// Metering is deterministic: the same program and budget always stop at the same point
+    pub fn run_with_budget(&mut self, budget: u64) -> Result<Step, u8> {
+        let mut left = budget;
         loop {
+            // a missing instruction is reported by run_once
+            if let Some(&byte) = self.program.get(self.pc) {
+                let cost = self.costs.cost(byte);
+                if cost > left {
+                    break Ok(Step::BudgetExhausted);
+                }
+                left -= cost;
+            }
             match self.run_once() {
                 Err(err) => break Err(err),
-                Ok(Step::Done) => break Ok(()),
                 Ok(Step::Continue) => (),
+                Ok(step) => break Ok(step),
             }
         }
     }
 
     pub fn run_once(&mut self) -> Result<Step, u8> {
+        self.instruction_pc = self.pc;
         if self.pc >= self.program.len() {
-            println!("pc overflow");
+            let message = format!("pc overflow at {}", self.location(self.pc));
+            self.notify(|observer, vm| observer.on_error(vm, 1, &message));
             return Err(1);
         }
+        if let Some(max) = self.limits.max_instructions {
+            if self.executed >= max {
+                let message = format!(
+                    "instruction limit of {} exceeded at {}",
+                    max,
+                    self.location(self.pc)
+                );
+                self.notify(|observer, vm| observer.on_error(vm, 3, &message));
+                return Err(3);
+            }
+        }
+        if let Some(profile) = self.profile.as_mut() {
+            let pc = self.pc;
+            let label = self
+                .debug_info
+                .as_ref()
+                .and_then(|debug_info| debug_info.symbol(pc))
+                .map(|symbol| symbol.name.as_str());
+            profile.record(pc, &Opcode::from(self.program[pc]), label);
+        }
+        if self.recording {
+            self.undo_log.push(UndoEntry {
+                pc: self.pc,
+                equal_flag: self.equal_flag,
+                remainder: self.remainder,
+                consumed: self.consumed,
+                exit_code: self.exit_code,
+                registers: vec![],
+                float_registers: vec![],
+                vector_registers: vec![],
+                memory_len: self.memory.len(),
+                allocated: self.allocated,
+                stack_len: self.call_stack.len(),
+                stack_top: self.call_stack.last().copied(),
+                memory: vec![],
+            });
+        }
+        self.executed += 1;
+        self.consumed += self.costs.cost(self.program[self.pc]);
 
-        match self.decode_opcode() {
-            Opcode::HLT => {
-                println!("HLT encountered");
+        let traced = self.tracer.as_ref().map(|_| {
+            let instruction = disassemble_one(&self.program, self.pc)
+                .map(|(text, _)| text)
+                .unwrap_or_else(|| "<truncated>".to_string());
+            (instruction, self.equal_flag, self.remainder)
+        });
+        self.trace_writes.clear();
+        self.trace_memory.clear();
+
+        let pc = self.pc;
+        let opcode = Opcode::from(self.program[pc]);
+        self.notify(|observer, vm| observer.before_instruction(vm, pc, &opcode));
+        let result = self.execute();
+        if let Some((instruction, equal_flag, remainder)) = traced {
+            let record = TraceRecord {
+                pc: self.instruction_pc,
+                instruction,
+                writes: self.trace_writes.drain(..).collect(),
+                memory: self.trace_memory.drain(..).collect(),
+                equal_flag: Some((equal_flag, self.equal_flag)).filter(|(old, new)| old != new),
+                remainder: Some((remainder, self.remainder)).filter(|(old, new)| old != new),
+            };
+            // tracing is best effort, a closed stream stops it without failing the program
+            let failed = self
+                .tracer
+                .as_mut()
+                .is_some_and(|tracer| tracer.record(&record).is_err());
+            if failed {
+                self.tracer = None;
+            }
+        }
+        let step = result?;
+        self.notify(|observer, vm| observer.after_instruction(vm, pc, &opcode));
+        match self.watch_hit.take() {
+            Some(hit) => Ok(Step::Watchpoint(hit)),
+            None => Ok(step),
+        }
+    }
+
+    // reports a failure of the current instruction to the observers
+    fn fault<T>(&mut self, code: u8, message: String) -> Result<T, u8> {
+        let message = format!("{} at {}", message, self.location(self.instruction_pc));
+        self.notify(|observer, vm| observer.on_error(vm, code, &message));
+        Err(code)
+    }
+
+    fn execute(&mut self) -> Result<Step, u8> {
+        let instruction = match Instruction::decode(&self.program, self.pc) {
+            Some((instruction, size)) => {
+                self.pc += size;
+                instruction
+            }
+            None => {
+                self.pc = self.program.len();
+                return self.fault(16, "truncated instruction".to_string());
+            }
+        };
+        if let Some(register) = missing_register(&instruction) {
+            return self.fault(13, format!("no register {}", register));
+        }
+        match instruction {
+            Instruction::Hlt {} => {
+                self.exit_code = 0;
+                let pc = self.instruction_pc;
+                self.notify(|observer, vm| observer.on_halt(vm, pc));
+                Ok(Step::Done)
+            }
+            Instruction::Exit { code } => {
+                let status = self.reg(code);
+                if !(0..=MAX_EXIT_STATUS).contains(&status) {
+                    return self.fault(15, format!("exit status {} is out of range", status));
+                }
+                self.exit_code = status;
+                let pc = self.instruction_pc;
+                self.notify(|observer, vm| observer.on_halt(vm, pc));
                 Ok(Step::Done)
             }
-            Opcode::IGL(opcode) => {
-                println!("IGL {} encountered", opcode);
+            Instruction::Igl(opcode) => {
+                let message = format!(
+                    "IGL {} encountered at {}",
+                    opcode,
+                    self.location(self.instruction_pc)
+                );
+                self.notify(|observer, vm| observer.on_error(vm, 2, &message));
                 Err(2)
             }
-            Opcode::LOAD => {
-                let register = self.next_8_bits() as usize;
-                let number = self.next_16_bits() as u16;
-                self.registers[register] = number as i32;
+            Instruction::Load { dst, imm } => {
+                self.write_register(dst as usize, imm as i32);
                 Ok(Step::Continue)
             }
-            Opcode::ADD => {
-                let (reg_l, reg_r) = self.read_registers_pair();
-                self.registers[self.next_8_bits() as usize] = reg_l + reg_r;
+            // integer arithmetic wraps around on overflow
+            Instruction::Add { lhs, rhs, dst } => {
+                let value = self.reg(lhs).wrapping_add(self.reg(rhs));
+                self.write_register(dst as usize, value);
                 Ok(Step::Continue)
             }
-            Opcode::MUL => {
-                let (reg_l, reg_r) = self.read_registers_pair();
-                // TODO: handle overflow
-                self.registers[self.next_8_bits() as usize] = reg_l * reg_r;
+            Instruction::Mul { lhs, rhs, dst } => {
+                let value = self.reg(lhs).wrapping_mul(self.reg(rhs));
+                self.write_register(dst as usize, value);
                 Ok(Step::Continue)
             }
-            Opcode::SUB => {
-                let (reg_l, reg_r) = self.read_registers_pair();
-                self.registers[self.next_8_bits() as usize] = reg_l - reg_r;
+            Instruction::Sub { lhs, rhs, dst } => {
+                let value = self.reg(lhs).wrapping_sub(self.reg(rhs));
+                self.write_register(dst as usize, value);
                 Ok(Step::Continue)
             }
-            Opcode::DIV => {
-                let (reg_l, reg_r) = self.read_registers_pair();
-                self.registers[self.next_8_bits() as usize] = reg_l / reg_r;
-                self.remainder = (reg_l % reg_r) as u32;
+            Instruction::Div { lhs, rhs, dst } => {
+                let (reg_l, reg_r) = (self.reg(lhs), self.reg(rhs));
+                match (reg_l.checked_div(reg_r), reg_l.checked_rem(reg_r)) {
+                    (Some(quotient), Some(remainder)) => {
+                        self.write_register(dst as usize, quotient);
+                        self.remainder = remainder as u32;
+                        Ok(Step::Continue)
+                    }
+                    _ if reg_r == 0 => self.fault(14, "division by zero".to_string()),
+                    _ => self.fault(14, format!("{} / {} overflows", reg_l, reg_r)),
+                }
+            }
+            Instruction::Jmp { target } => {
+                self.pc = self.reg(target) as u32 as usize;
                 Ok(Step::Continue)
             }
-            Opcode::JMP => {
-                let jump = self.read_register();
-                self.pc = jump as usize;
+            Instruction::Jmpf { offset } => {
+                self.pc = self.pc.saturating_add(self.reg(offset) as u32 as usize);
                 Ok(Step::Continue)
             }
-            Opcode::JMPF => {
-                let value = self.read_register() as usize;
-                self.pc += value;
+            Instruction::Jmpb { offset } => {
+                let offset = self.reg(offset) as u32 as usize;
+                match self.pc.checked_sub(offset) {
+                    Some(pc) => {
+                        self.pc = pc;
+                        Ok(Step::Continue)
+                    }
+                    None => self.fault(17, format!("jump back by {} from {}", offset, self.pc)),
+                }
+            }
+            Instruction::Jeq { target } => {
+                if self.equal_flag {
+                    self.pc = self.reg(target) as u32 as usize;
+                }
                 Ok(Step::Continue)
             }
-            Opcode::JMPB => {
-                let value = self.read_register() as usize;
-                self.pc -= value;
+            Instruction::Eq { lhs, rhs } => {
+                self.equal_flag = self.reg(lhs) == self.reg(rhs);
                 Ok(Step::Continue)
             }
-            Opcode::JEQ => {
-                let jump = self.read_register();
-                if self.equal_flag {
-                    self.pc = jump as usize;
+            Instruction::Neq { lhs, rhs } => {
+                self.equal_flag = self.reg(lhs) != self.reg(rhs);
+                Ok(Step::Continue)
+            }
+            Instruction::Gt { lhs, rhs } => {
+                self.equal_flag = self.reg(lhs) > self.reg(rhs);
+                Ok(Step::Continue)
+            }
+            Instruction::Lt { lhs, rhs } => {
+                self.equal_flag = self.reg(lhs) < self.reg(rhs);
+                Ok(Step::Continue)
+            }
+            Instruction::Gtq { lhs, rhs } => {
+                self.equal_flag = self.reg(lhs) >= self.reg(rhs);
+                Ok(Step::Continue)
+            }
+            Instruction::Ltq { lhs, rhs } => {
+                self.equal_flag = self.reg(lhs) <= self.reg(rhs);
+                Ok(Step::Continue)
+            }
+            Instruction::Aloc { size } => {
+                let bytes = self.reg(size) as u32 as usize;
+                let allowed = self
+                    .limits
+                    .max_heap_bytes
+                    .is_none_or(|max| self.allocated + bytes <= max);
+                if !allowed || self.memory.try_reserve(bytes).is_err() {
+                    let message = format!(
+                        "allocating {} bytes after {} exceeds the heap quota",
+                        bytes, self.allocated
+                    );
+                    return self.fault(4, message);
                 }
+                self.memory.resize(self.memory.len() + bytes, 0);
+                self.allocated += bytes;
                 Ok(Step::Continue)
             }
-            Opcode::EQ => {
-                self.equal_flag = {
-                    let (reg_l, reg_r) = self.read_registers_pair();
-                    reg_l == reg_r
+            Instruction::Ldw { addr, dst } => {
+                let address = self.reg(addr) as u32 as usize;
+                let word = match self.memory.get(address..address + 4) {
+                    Some(bytes) => i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+                    None => return self.fault(10, format!("no word at address {}", address)),
                 };
+                self.write_register(dst as usize, word);
                 Ok(Step::Continue)
             }
-            Opcode::NEQ => {
-                self.equal_flag = {
-                    let (reg_l, reg_r) = self.read_registers_pair();
-                    reg_l != reg_r
-                };
+            Instruction::Loadf { dst, value } => {
+                self.write_float_register(dst as usize, value);
                 Ok(Step::Continue)
             }
-            Opcode::GT => {
-                self.equal_flag = {
-                    let (reg_l, reg_r) = self.read_registers_pair();
-                    reg_l > reg_r
-                };
+            Instruction::Fadd { lhs, rhs, dst } => {
+                self.write_float_register(dst as usize, self.freg(lhs) + self.freg(rhs));
                 Ok(Step::Continue)
             }
-            Opcode::LT => {
-                self.equal_flag = {
-                    let (reg_l, reg_r) = self.read_registers_pair();
-                    reg_l < reg_r
-                };
+            Instruction::Fsub { lhs, rhs, dst } => {
+                self.write_float_register(dst as usize, self.freg(lhs) - self.freg(rhs));
+                Ok(Step::Continue)
+            }
+            Instruction::Fmul { lhs, rhs, dst } => {
+                self.write_float_register(dst as usize, self.freg(lhs) * self.freg(rhs));
                 Ok(Step::Continue)
             }
-            Opcode::GTQ => {
-                self.equal_flag = {
-                    let (reg_l, reg_r) = self.read_registers_pair();
-                    reg_l >= reg_r
+            Instruction::Fdiv { lhs, rhs, dst } => {
+                self.write_float_register(dst as usize, self.freg(lhs) / self.freg(rhs));
+                Ok(Step::Continue)
+            }
+            Instruction::Feq { lhs, rhs } => {
+                self.equal_flag = self.freg(lhs) == self.freg(rhs);
+                Ok(Step::Continue)
+            }
+            Instruction::Flt { lhs, rhs } => {
+                self.equal_flag = self.freg(lhs) < self.freg(rhs);
+                Ok(Step::Continue)
+            }
+            Instruction::Itof { src, dst } => {
+                self.write_float_register(dst as usize, f64::from(self.reg(src)));
+                Ok(Step::Continue)
+            }
+            Instruction::Ftoi { src, dst } => {
+                // truncates toward zero, saturating out of range values and NaN to 0
+                self.write_register(dst as usize, self.freg(src) as i32);
+                Ok(Step::Continue)
+            }
+            Instruction::Vld { addr, dst } => {
+                let address = self.reg(addr) as u32 as usize;
+                let bytes = match self.memory.get(address..address + 4 * LANES) {
+                    Some(bytes) => bytes,
+                    None => return self.fault(10, format!("no vector at address {}", address)),
                 };
+                let mut vector = [0; LANES];
+                for (lane, word) in vector.iter_mut().zip(bytes.chunks(4)) {
+                    *lane = i32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+                }
+                self.write_vector_register(dst as usize, vector);
+                Ok(Step::Continue)
+            }
+            Instruction::Vst { src, addr } => {
+                let address = self.reg(addr) as u32 as usize;
+                if address + 4 * LANES > self.memory.len() {
+                    return self.fault(10, format!("no vector at address {}", address));
+                }
+                let bytes: Vec<u8> = self
+                    .vreg(src)
+                    .iter()
+                    .flat_map(|lane| lane.to_be_bytes())
+                    .collect();
+                self.write_memory(address, &bytes);
+                Ok(Step::Continue)
+            }
+            Instruction::Vadd { lhs, rhs, dst } => {
+                self.lane_wise(lhs, rhs, dst, |l, r| l.wrapping_add(r));
+                Ok(Step::Continue)
+            }
+            Instruction::Vmul { lhs, rhs, dst } => {
+                self.lane_wise(lhs, rhs, dst, |l, r| l.wrapping_mul(r));
+                Ok(Step::Continue)
+            }
+            Instruction::Veq { lhs, rhs, dst } => {
+                let mask = self.lane_wise(lhs, rhs, dst, |l, r| (l == r) as i32);
+                self.equal_flag = mask.iter().all(|&lane| lane == 1);
+                Ok(Step::Continue)
+            }
+            Instruction::Vlt { lhs, rhs, dst } => {
+                let mask = self.lane_wise(lhs, rhs, dst, |l, r| (l < r) as i32);
+                self.equal_flag = mask.iter().all(|&lane| lane == 1);
+                Ok(Step::Continue)
+            }
+            Instruction::Vsum { src, dst } => {
+                let sum = self
+                    .vreg(src)
+                    .iter()
+                    .fold(0i32, |sum, &lane| sum.wrapping_add(lane));
+                self.write_register(dst as usize, sum);
+                Ok(Step::Continue)
+            }
+            Instruction::Vshuf { src, dst, selector } => {
+                let source = self.vreg(src);
+                // two bits per lane select the source lane, lane 0 in the lowest bits
+                let mut vector = [0; LANES];
+                for (index, lane) in vector.iter_mut().enumerate() {
+                    *lane = source[(selector >> (2 * index)) as usize & 3];
+                }
+                self.write_vector_register(dst as usize, vector);
+                Ok(Step::Continue)
+            }
+            Instruction::Call { target } => {
+                let target = self.reg(target) as u32 as usize;
+                if let Some(max) = self.limits.max_stack_depth {
+                    if self.call_stack.len() >= max {
+                        let message = format!("call depth of {} exceeds the stack quota", max + 1);
+                        return self.fault(5, message);
+                    }
+                }
+                self.call_stack.push(self.pc);
+                self.pc = target;
                 Ok(Step::Continue)
             }
-            Opcode::LTQ => {
-                self.equal_flag = {
-                    let (reg_l, reg_r) = self.read_registers_pair();
-                    reg_l <= reg_r
+            Instruction::Ret {} => match self.call_stack.pop() {
+                Some(address) => {
+                    self.pc = address;
+                    Ok(Step::Continue)
+                }
+                None => self.fault(7, "RET with an empty call stack".to_string()),
+            },
+            Instruction::Sys { number } => {
+                // detached while running so that it can borrow the VM
+                let mut syscall = match self.syscalls.remove(&number) {
+                    Some(syscall) => syscall,
+                    None => return self.fault(8, format!("unregistered syscall #{}", number)),
+                };
+                let result = syscall(self);
+                self.syscalls.entry(number).or_insert(syscall);
+                match result {
+                    Ok(value) => {
+                        self.write_register(SYSCALL_RESULT, value);
+                        Ok(Step::Continue)
+                    }
+                    Err(err) => self.fault(9, format!("syscall #{} failed: {}", number, err)),
+                }
+            }
+            Instruction::Prti { src } => {
+                let value = self.reg(src);
+                self.print(value.to_string().as_bytes())
+            }
+            Instruction::Prtc { src } => {
+                let value = self.reg(src);
+                match char::from_u32(value as u32) {
+                    Some(c) => self.print(c.to_string().as_bytes()),
+                    None => self.fault(11, format!("{} is not a character", value)),
+                }
+            }
+            Instruction::Prts { addr } => {
+                let address = self.reg(addr) as u32 as usize;
+                let text = self.memory.get(address..).and_then(|tail| {
+                    tail.iter()
+                        .position(|&byte| byte == 0)
+                        .map(|end| tail[..end].to_vec())
+                });
+                match text {
+                    Some(text) => self.print(&text),
+                    None => self.fault(10, format!("no string at address {}", address)),
+                }
+            }
+            Instruction::Rdi { dst } => {
+                let line = match self.read_line()? {
+                    Some(line) => line,
+                    None => return self.fault(11, "end of input".to_string()),
                 };
+                match line.trim().parse() {
+                    Ok(value) => {
+                        self.write_register(dst as usize, value);
+                        Ok(Step::Continue)
+                    }
+                    Err(_) => self.fault(11, format!("`{}` is not an integer", line.trim())),
+                }
+            }
+            Instruction::Rds { addr, len } => {
+                let address = self.reg(addr) as u32 as usize;
+                let len = self.reg(len) as u32 as usize;
+                if len == 0 || address + len > self.memory.len() {
+                    let message = format!("{} bytes at address {} are out of bounds", len, address);
+                    return self.fault(10, message);
+                }
+                let line = self.read_line()?;
+                self.equal_flag = line.is_some();
+                let mut bytes = line.unwrap_or_default().into_bytes();
+                bytes.truncate(len - 1);
+                bytes.push(0);
+                self.write_memory(address, &bytes);
                 Ok(Step::Continue)
             }
         }
     }
 
-    fn decode_opcode(&mut self) -> Opcode {
-        let opcode = Opcode::from(self.program[self.pc]);
-        self.pc += 1;
-        opcode
+    fn print(&mut self, bytes: &[u8]) -> Result<Step, u8> {
+        let output = &mut self.console.output;
+        match output.write_all(bytes).and_then(|_| output.flush()) {
+            Ok(()) => Ok(Step::Continue),
+            Err(err) => self.fault(12, format!("writing output failed: {}", err)),
+        }
     }
 
-    fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
-        self.pc += 1;
-        result
+    // next input line without its line break, `None` at the end of input
+    fn read_line(&mut self) -> Result<Option<String>, u8> {
+        let mut line = String::new();
+        match self.console.input.read_line(&mut line) {
+            Ok(0) => Ok(None),
+            Ok(_) => {
+                let end = line.trim_end_matches(&['\r', '\n'][..]).len();
+                line.truncate(end);
+                Ok(Some(line))
+            }
+            Err(err) => self.fault(12, format!("reading input failed: {}", err)),
+        }
     }
 
-    fn next_16_bits(&mut self) -> u16 {
-        let result = ((self.program[self.pc] as u16) << 8) | self.program[self.pc + 1] as u16;
-        self.pc += 2;
+    fn write_memory(&mut self, address: usize, bytes: &[u8]) {
+        let target = &mut self.memory[address..address + bytes.len()];
+        let old = target.to_vec();
+        target.copy_from_slice(bytes);
+        if self.recording {
+            if let Some(entry) = self.undo_log.entries.back_mut() {
+                entry
+                    .memory
+                    .extend(old.iter().enumerate().map(|(i, &old)| (address + i, old)));
+            }
+        }
+        self.notify(|observer, vm| observer.on_memory_write(vm, address, &old, bytes));
+        self.check_memory_watchpoints(address, &old, bytes);
+        if self.tracer.is_some() {
+            self.trace_memory.push(MemoryWrite {
+                address,
+                old,
+                new: bytes.to_vec(),
+            });
+        }
+    }
+
+    fn write_register(&mut self, register: usize, value: i32) {
+        let old = self.registers[register];
+        self.registers[register] = value;
+        if self.recording {
+            if let Some(entry) = self.undo_log.entries.back_mut() {
+                entry.registers.push((register, old));
+            }
+        }
+        self.notify(|observer, vm| observer.on_register_write(vm, register, old, value));
+        self.report_write(Register::Int(register), Value::Int(old), Value::Int(value));
+    }
+
+    fn write_float_register(&mut self, register: usize, value: f64) {
+        let old = self.float_registers[register];
+        self.float_registers[register] = value;
+        if self.recording {
+            if let Some(entry) = self.undo_log.entries.back_mut() {
+                entry.float_registers.push((register, old));
+            }
+        }
+        self.notify(|observer, vm| observer.on_float_register_write(vm, register, old, value));
+        self.report_write(
+            Register::Float(register),
+            Value::Float(old),
+            Value::Float(value),
+        );
+    }
+
+    // hands a register write to the tracer and the watchpoints
+    fn report_write(&mut self, register: Register, old: Value, new: Value) {
+        if self.tracer.is_some() {
+            self.trace_writes.push(RegisterWrite { register, old, new });
+        }
+        let triggered = self.watchpoints.iter().any(|watch| match watch {
+            Watchpoint::Register {
+                register: watched,
+                kind,
+            } => *watched == register && (*kind == WatchKind::Write || old != new),
+            Watchpoint::Memory { .. } => false,
+        });
+        if triggered {
+            self.watch_hit = Some(WatchHit::Register {
+                register,
+                old,
+                new,
+                pc: self.instruction_pc,
+            });
+        }
+    }
+
+    // stops on the first memory watchpoint the store of `new` over `old` at `address` hits,
+    // reporting the watched part of the store
+    fn check_memory_watchpoints(&mut self, address: usize, old: &[u8], new: &[u8]) {
+        for watch in &self.watchpoints {
+            let (start, len, kind) = match watch {
+                Watchpoint::Memory { start, len, kind } => (*start, *len, *kind),
+                Watchpoint::Register { .. } => continue,
+            };
+            let from = start.max(address);
+            let to = (start + len).min(address + new.len());
+            if from >= to {
+                continue;
+            }
+            let (old, new) = (
+                &old[from - address..to - address],
+                &new[from - address..to - address],
+            );
+            if kind == WatchKind::Write || old != new {
+                self.watch_hit = Some(WatchHit::Memory {
+                    address: from,
+                    old: old.to_vec(),
+                    new: new.to_vec(),
+                    pc: self.instruction_pc,
+                });
+                return;
+            }
+        }
+    }
+
+    fn write_vector_register(&mut self, register: usize, value: Vector) {
+        let old = self.vector_registers[register];
+        self.vector_registers[register] = value;
+        if self.recording {
+            if let Some(entry) = self.undo_log.entries.back_mut() {
+                entry.vector_registers.push((register, old));
+            }
+        }
+        self.notify(|observer, vm| observer.on_vector_register_write(vm, register, old, value));
+        self.report_write(
+            Register::Vector(register),
+            Value::Vector(old),
+            Value::Vector(value),
+        );
+    }
+
+    // applies `op` to every pair of lanes of two vector registers into a third one
+    fn lane_wise(&mut self, lhs: u8, rhs: u8, dst: u8, op: impl Fn(i32, i32) -> i32) -> Vector {
+        let (left, right) = (self.vreg(lhs), self.vreg(rhs));
+        let mut result = [0; LANES];
+        for (lane, value) in result.iter_mut().enumerate() {
+            *value = op(left[lane], right[lane]);
+        }
+        self.write_vector_register(dst as usize, result);
         result
     }
 
-    fn read_registers_pair(&mut self) -> (i32, i32) {
-        (self.read_register(), self.read_register())
+    fn reg(&self, register: u8) -> i32 {
+        self.registers[register as usize]
     }
 
-    // return value of a register that specified in the next program unit
-    fn read_register(&mut self) -> i32 {
-        self.registers[self.next_8_bits() as usize]
+    fn freg(&self, register: u8) -> f64 {
+        self.float_registers[register as usize]
     }
+
+    fn vreg(&self, register: u8) -> Vector {
+        self.vector_registers[register as usize]
+    }
+}
+
+// register operand of `instruction` the VM doesn't have
+fn missing_register(instruction: &Instruction) -> Option<Operand> {
+    instruction
+        .operands()
+        .into_iter()
+        .find(|operand| match *operand {
+            Operand::Register(register) => register as usize >= REGISTER_COUNT,
+            Operand::FloatRegister(register) => register as usize >= FLOAT_REGISTER_COUNT,
+            Operand::VectorRegister(register) => register as usize >= VECTOR_REGISTER_COUNT,
+            Operand::Integer(_) | Operand::Float(_) => false,
+        })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::{Arc, Mutex};
 
     #[test]
     fn test_create_vm() {
@@ -193,7 +1562,7 @@ mod tests {
         let mut test_vm = VM::new();
         let test_bytes = vec![0, 0, 0, 0];
         test_vm.program = test_bytes;
-        assert_eq!(test_vm.run(), Ok(()));
+        assert_eq!(test_vm.run(), Ok(Step::Done));
         assert_eq!(test_vm.pc, 1);
     }
 
@@ -206,11 +1575,32 @@ mod tests {
         assert_eq!(test_vm.pc, 1);
     }
 
+    #[test]
+    fn test_error_location() {
+        let mut test_vm = VM::new();
+        test_vm
+            .load(
+                crate::assembler::assemble("loop.s", "load $0 #1\nloop: add $0 $0 $0\nigl\n")
+                    .unwrap(),
+            )
+            .unwrap();
+        assert_eq!(test_vm.run(), Err(2));
+        assert_eq!(
+            test_vm.error_message(2),
+            "illegal opcode at loop.s:3 (in `loop`)"
+        );
+
+        let mut test_vm = VM::new();
+        test_vm.program = vec![200];
+        assert_eq!(test_vm.run(), Err(2));
+        assert_eq!(test_vm.error_message(2), "illegal opcode at pc 0");
+    }
+
     #[test]
     fn test_load_opcode() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 0, 1, 244, 0];
-        assert_eq!(test_vm.run(), Ok(()));
+        assert_eq!(test_vm.run(), Ok(Step::Done));
         assert_eq!(test_vm.registers[0], 500);
     }
 
@@ -218,7 +1608,7 @@ mod tests {
     fn test_load_add() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 0, 1, 244, 1, 1, 1, 245, 2, 0, 1, 4, 0];
-        assert_eq!(test_vm.run(), Ok(()));
+        assert_eq!(test_vm.run(), Ok(Step::Done));
         assert_eq!(test_vm.registers[4], 1001);
     }
 
@@ -226,7 +1616,7 @@ mod tests {
     fn test_load_sub() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 0, 1, 244, 1, 1, 1, 245, 3, 1, 0, 4, 0];
-        assert_eq!(test_vm.run(), Ok(()));
+        assert_eq!(test_vm.run(), Ok(Step::Done));
         assert_eq!(test_vm.registers[4], 1);
     }
 
@@ -234,7 +1624,7 @@ mod tests {
     fn test_load_mul() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 0, 1, 244, 1, 1, 1, 245, 4, 1, 0, 5, 0];
-        assert_eq!(test_vm.run(), Ok(()));
+        assert_eq!(test_vm.run(), Ok(Step::Done));
         assert_eq!(test_vm.registers[5], 250_500);
     }
 
@@ -242,11 +1632,750 @@ mod tests {
     fn test_load_div() {
         let mut test_vm = VM::new();
         test_vm.program = vec![1, 0, 1, 246, 1, 1, 1, 244, 5, 0, 1, 5, 0];
-        assert_eq!(test_vm.run(), Ok(()));
+        assert_eq!(test_vm.run(), Ok(Step::Done));
         assert_eq!(test_vm.registers[5], 1);
         assert_eq!(test_vm.remainder, 2);
     }
 
+    #[test]
+    fn test_arithmetic_faults() {
+        let mut test_vm = VM::builder().quiet().build();
+        test_vm.registers[0] = i32::MIN;
+        test_vm.registers[1] = -1;
+        // DIV $0 $2 $3 divides by zero, DIV $0 $1 $3 overflows
+        for program in [vec![5, 0, 2, 3], vec![5, 0, 1, 3]] {
+            test_vm.load(Image::new(program)).unwrap();
+            assert_eq!(test_vm.run(), Err(14));
+            assert_eq!(test_vm.registers[3], 0);
+        }
+        assert_eq!(test_vm.error_message(14), "division fault at pc 0");
+
+        // SUB $0 $1 $3; MUL $0 $1 $4; ADD $3 $1 $5; HLT
+        let program = vec![3, 0, 1, 3, 4, 0, 1, 4, 2, 3, 1, 5, 0];
+        test_vm.load(Image::new(program)).unwrap();
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!(test_vm.registers[3..6], [i32::MIN + 1, i32::MIN, i32::MIN]);
+
+        // JMPB $1 jumps back by 4 GiB
+        test_vm.load(Image::new(vec![8, 1])).unwrap();
+        assert_eq!(test_vm.run(), Err(17));
+        assert_eq!(
+            test_vm.error_message(17),
+            "jump before the start of the program at pc 0"
+        );
+    }
+
+    #[test]
+    fn test_invalid_register() {
+        let mut test_vm = VM::builder().quiet().build();
+        // ADD $40 $0 $0, LOADF $f40 #0, VSUM $v20 $0, EXIT $32
+        for program in [
+            vec![2, 40, 0, 0],
+            vec![0x1B, 40, 0, 0, 0, 0, 0, 0, 0, 0],
+            vec![0x2A, 20, 0],
+            vec![0x19, 32],
+        ] {
+            test_vm.load(Image::new(program)).unwrap();
+            assert_eq!(test_vm.run(), Err(13));
+        }
+        assert_eq!(test_vm.error_message(13), "invalid register at pc 0");
+        test_vm.load(Image::new(vec![0x2A, 15, 32])).unwrap();
+        assert_eq!(test_vm.run(), Err(13));
+    }
+
+    #[test]
+    fn test_watchpoint_on_write() {
+        let mut test_vm = VM::new();
+        // LOAD $0 #1; LOAD $0 #1; ADD $0 $0 $1; HLT
+        test_vm.program = vec![1, 0, 0, 1, 1, 0, 0, 1, 2, 0, 0, 1, 0];
+        test_vm.add_watchpoint(Register::Int(0), WatchKind::Write);
+        let hit = |old, new, pc| {
+            Ok(Step::Watchpoint(WatchHit::Register {
+                register: Register::Int(0),
+                old: Value::Int(old),
+                new: Value::Int(new),
+                pc,
+            }))
+        };
+        assert_eq!(test_vm.run(), hit(0, 1, 0));
+        assert_eq!(test_vm.run(), hit(1, 1, 4));
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!(test_vm.registers[1], 2);
+    }
+
+    #[test]
+    fn test_watchpoint_on_change() {
+        let mut test_vm = VM::new();
+        // LOAD $0 #1; LOAD $0 #1; ADD $0 $0 $0; HLT
+        test_vm.program = vec![1, 0, 0, 1, 1, 0, 0, 1, 2, 0, 0, 0, 0];
+        test_vm.add_watchpoint(Register::Int(0), WatchKind::Change);
+        assert!(matches!(
+            test_vm.run(),
+            Ok(Step::Watchpoint(WatchHit::Register { pc: 0, .. }))
+        ));
+        assert!(matches!(
+            test_vm.run(),
+            Ok(Step::Watchpoint(WatchHit::Register {
+                old: Value::Int(1),
+                new: Value::Int(2),
+                pc: 8,
+                ..
+            }))
+        ));
+        assert!(test_vm.remove_watchpoint(Register::Int(0)));
+        assert!(!test_vm.remove_watchpoint(Register::Int(0)));
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+    }
+
+    #[test]
+    fn test_watchpoint_on_memory() {
+        let mut test_vm = VM::builder()
+            .memory_size(32)
+            .input(Box::new(&b"b\n"[..]))
+            .quiet()
+            .build();
+        let source = "load $2 #16\nvld $0 $v1\nvst $v1 $2\nload $3 #20\nload $4 #4\n\
+            rds $3 $4\nhlt\n";
+        test_vm
+            .load(crate::assembler::assemble("mem.s", source).unwrap())
+            .unwrap();
+        test_vm.memory[3] = 7;
+        test_vm.add_memory_watchpoint(18, 2, WatchKind::Write);
+        // VST also stores zeros over these, which isn't a change
+        test_vm.add_memory_watchpoint(20, 3, WatchKind::Change);
+        assert_eq!(
+            test_vm.run(),
+            Ok(Step::Watchpoint(WatchHit::Memory {
+                address: 18,
+                old: vec![0, 0],
+                new: vec![0, 7],
+                pc: 7,
+            }))
+        );
+        assert_eq!(
+            test_vm.run(),
+            Ok(Step::Watchpoint(WatchHit::Memory {
+                address: 20,
+                old: vec![0, 0],
+                new: vec![b'b', 0],
+                pc: 18,
+            }))
+        );
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert!(test_vm.remove_memory_watchpoint(18));
+        assert!(!test_vm.remove_memory_watchpoint(18));
+        assert_eq!(test_vm.watchpoints().len(), 1);
+    }
+
+    #[test]
+    fn test_watchpoint_on_float_write() {
+        let mut test_vm = VM::new();
+        test_vm
+            .load(crate::assembler::assemble("float.s", "loadf $f1 #2.5\nhlt\n").unwrap())
+            .unwrap();
+        test_vm.add_watchpoint(Register::Float(1), WatchKind::Write);
+        assert_eq!(
+            test_vm.run(),
+            Ok(Step::Watchpoint(WatchHit::Register {
+                register: Register::Float(1),
+                old: Value::Float(0.0),
+                new: Value::Float(2.5),
+                pc: 0,
+            }))
+        );
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+    }
+
+    #[test]
+    fn test_builder_limits() {
+        let mut test_vm = VM::builder()
+            .memory_size(16)
+            .limits(Limits {
+                max_instructions: Some(3),
+                ..Limits::default()
+            })
+            .quiet()
+            .build();
+        assert_eq!(test_vm.memory(), &[0; 16]);
+        // jmp $0 forever
+        test_vm.program = vec![6, 0];
+        assert_eq!(test_vm.run(), Err(3));
+        assert_eq!(test_vm.executed(), 3);
+        assert_eq!(
+            test_vm.error_message(3),
+            "instruction limit exceeded at pc 0"
+        );
+    }
+
+    fn quota_vm(limits: Limits, source: &str) -> VM {
+        let mut test_vm = VM::builder().limits(limits).quiet().build();
+        test_vm
+            .load(crate::assembler::assemble("quota.s", source).unwrap())
+            .unwrap();
+        test_vm
+    }
+
+    #[test]
+    fn test_heap_quota() {
+        let limits = Limits {
+            max_heap_bytes: Some(16),
+            ..Limits::default()
+        };
+        let mut test_vm = quota_vm(limits, "load $0 #10\naloc $0\naloc $0\nhlt\n");
+        assert_eq!(test_vm.run(), Err(4));
+        assert_eq!(test_vm.allocated(), 10);
+        assert_eq!(test_vm.memory().len(), 10);
+        assert_eq!(test_vm.error_message(4), "heap quota exceeded at quota.s:3");
+    }
+
+    #[test]
+    fn test_stack_quota() {
+        let source = "load $0 @sub\ncall $0\nhlt\nsub: call $0\n";
+        let limits = Limits {
+            max_stack_depth: Some(3),
+            ..Limits::default()
+        };
+        let mut test_vm = quota_vm(limits, source);
+        assert_eq!(test_vm.run(), Err(5));
+        assert_eq!(test_vm.call_stack(), &[6, 9, 9]);
+
+        let mut test_vm = quota_vm(Limits::default(), "load $0 @sub\ncall $0\nhlt\nsub: ret\n");
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert!(test_vm.call_stack().is_empty());
+        // falls through into `sub`
+        assert_eq!(test_vm.run_once(), Err(7));
+    }
+
+    #[test]
+    fn test_program_quota() {
+        let mut test_vm = VM::builder()
+            .limits(Limits {
+                max_program_size: Some(4),
+                ..Limits::default()
+            })
+            .quiet()
+            .build();
+        assert_eq!(test_vm.load(Image::new(vec![0; 5])), Err(6));
+        assert!(test_vm.program().is_empty());
+        assert_eq!(test_vm.load(Image::new(vec![0; 4])), Ok(()));
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut test_vm = VM::builder()
+            .limits(Limits {
+                max_heap_bytes: Some(8),
+                ..Limits::default()
+            })
+            .quiet()
+            .build();
+        test_vm.set_recording(true);
+        // LOAD $0 #8; ALOC $0; HLT
+        let program = vec![1, 0, 0, 8, 0x10, 0, 0];
+        test_vm.load(Image::new(program.clone())).unwrap();
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!((test_vm.allocated(), test_vm.history_len()), (8, 3));
+
+        test_vm.load(Image::new(program)).unwrap();
+        assert_eq!(test_vm.allocated(), 0);
+        assert_eq!((test_vm.executed(), test_vm.history_len()), (0, 0));
+        assert_eq!(test_vm.registers[0], 8);
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!(test_vm.memory().len(), 16);
+    }
+
+    #[test]
+    fn test_step_back_calls() {
+        let source = "load $1 #4\nload $0 @sub\ncall $0\nhlt\nsub: aloc $1\nret\n";
+        let mut test_vm = quota_vm(Limits::default(), source);
+        test_vm.set_recording(true);
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!(test_vm.allocated(), 4);
+        // hlt, ret
+        assert!(test_vm.step_back());
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.call_stack(), &[10]);
+        // aloc
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.allocated(), 0);
+        assert!(test_vm.memory().is_empty());
+        // call
+        assert!(test_vm.step_back());
+        assert!(test_vm.call_stack().is_empty());
+        assert_eq!(test_vm.pc(), 8);
+    }
+
+    #[test]
+    fn test_syscalls() {
+        let mut test_vm = quota_vm(Limits::default(), "load $1 #3\nsys #1\nsys #2\nsys #7\n");
+        let mut calls = 0;
+        test_vm.register_syscall(1, move |vm| {
+            calls += 1;
+            Ok(vm.registers()[SYSCALL_ARGS[0]] * 10 + calls)
+        });
+        test_vm.register_syscall(2, |vm| match vm.registers()[SYSCALL_RESULT] {
+            31 => Err("no more".to_string()),
+            _ => Ok(0),
+        });
+        assert_eq!(test_vm.run_once(), Ok(Step::Continue));
+        assert_eq!(test_vm.run_once(), Ok(Step::Continue));
+        assert_eq!(test_vm.registers[SYSCALL_RESULT], 31);
+        assert_eq!(test_vm.run(), Err(9));
+        assert_eq!(test_vm.error_message(9), "syscall failed at quota.s:3");
+        assert_eq!(test_vm.run(), Err(8));
+        assert_eq!(
+            test_vm.error_message(8),
+            "unregistered syscall at quota.s:4"
+        );
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let mut test_vm = quota_vm(Limits::default(), "load $2 #3\nexit $2\nhlt\n");
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!(test_vm.exit_code(), 3);
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!(test_vm.exit_code(), 0);
+
+        let mut test_vm = quota_vm(
+            Limits::default(),
+            "load $2 #99
+exit $2
+load $2 #100
+exit $2
+",
+        );
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!(test_vm.exit_code(), 99);
+        assert_eq!(test_vm.run(), Err(15));
+        assert_eq!(test_vm.exit_code(), 99);
+        test_vm.registers[2] = -1;
+        test_vm.set_pc(6);
+        assert_eq!(test_vm.run(), Err(15));
+    }
+
+    #[test]
+    fn test_set_args() {
+        let output = Buffer::default();
+        let mut test_vm = VM::builder()
+            .output(Box::new(output.clone()))
+            .quiet()
+            .build();
+        let source = "load $4 #4\nadd $1 $4 $5\nldw $5 $6\nprts $6\nldw $3 $7\nprts $7\nhlt\n";
+        test_vm
+            .load(crate::assembler::assemble("args.s", source).unwrap())
+            .unwrap();
+        test_vm.set_args(&["prog", "10"], &["HOME=/root"]);
+        assert_eq!(test_vm.registers()[ARGC], 2);
+        assert_eq!(test_vm.registers()[ARGV], 0);
+        assert_eq!(test_vm.registers()[ENVC], 1);
+        assert_eq!(test_vm.registers()[ENVP], 16);
+        assert_eq!(&test_vm.memory()[..8], &[0, 0, 0, 8, 0, 0, 0, 13]);
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!(
+            String::from_utf8(output.0.lock().unwrap().clone()).unwrap(),
+            "10HOME=/root"
+        );
+        test_vm.registers[3] = 100;
+        test_vm.set_pc(13);
+        assert_eq!(test_vm.run(), Err(10));
+    }
+
+    #[test]
+    fn test_float_opcodes() {
+        let source = "loadf $f0 #1.5\nloadf $f1 #-0.25\nfmul $f0 $f1 $f2\nfsub $f0 $f2 $f3\n\
+            fdiv $f3 $f1 $f4\nfadd $f4 $f0 $f5\nftoi $f5 $0\nitof $0 $f6\nflt $f6 $f5\nhlt\n";
+        let mut test_vm = quota_vm(Limits::default(), source);
+        test_vm.set_recording(true);
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!(test_vm.float_registers[2], -0.375);
+        assert_eq!(test_vm.float_registers[3], 1.875);
+        assert_eq!(test_vm.float_registers[4], -7.5);
+        assert_eq!(test_vm.float_registers[5], -6.0);
+        assert_eq!(test_vm.registers[0], -6);
+        assert!(!test_vm.equal_flag());
+        test_vm.float_registers[7] = f64::NAN;
+        test_vm.program = vec![0x20, 7, 7, 0x23, 7, 1, 0];
+        test_vm.set_pc(0);
+        test_vm.registers[1] = 5;
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert!(!test_vm.equal_flag());
+        assert_eq!(test_vm.registers[1], 0);
+    }
+
+    #[test]
+    fn test_step_back_float() {
+        let mut test_vm = quota_vm(Limits::default(), "loadf $f1 #2.5\nhlt\n");
+        test_vm.set_recording(true);
+        assert_eq!(test_vm.run_once(), Ok(Step::Continue));
+        assert_eq!(test_vm.float_registers()[1], 2.5);
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.float_registers()[1], 0.0);
+    }
+
+    #[test]
+    fn test_vector_opcodes() {
+        let source = "load $1 #16\nvld $0 $v0\nvld $1 $v1\nvadd $v0 $v1 $v2\nvmul $v0 $v1 $v3\n\
+            vsum $v3 $2\nvshuf $v0 $v4 #27\nveq $v0 $v1 $v5\nvlt $v0 $v1 $v6\n\
+            load $3 #32\nvst $v2 $3\nhlt\n";
+        let mut test_vm = VM::builder().memory_size(48).quiet().build();
+        test_vm
+            .load(crate::assembler::assemble("vec.s", source).unwrap())
+            .unwrap();
+        let lanes: Vec<u8> = [1, 2, 3, 4, 5, 2, 7, 8]
+            .iter()
+            .flat_map(|lane: &i32| lane.to_be_bytes())
+            .collect();
+        test_vm.memory[..32].copy_from_slice(&lanes);
+        test_vm.set_recording(true);
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!(test_vm.vector_registers[2], [6, 4, 10, 12]);
+        assert_eq!(test_vm.vector_registers[3], [5, 4, 21, 32]);
+        assert_eq!(test_vm.registers[2], 62);
+        assert_eq!(test_vm.vector_registers[4], [4, 3, 2, 1]);
+        assert_eq!(test_vm.vector_registers[5], [0, 1, 0, 0]);
+        assert_eq!(test_vm.vector_registers[6], [1, 0, 1, 1]);
+        assert!(!test_vm.equal_flag());
+        assert_eq!(&test_vm.memory()[32..40], &[0, 0, 0, 6, 0, 0, 0, 4]);
+        assert!(test_vm.step_back());
+        assert!(test_vm.step_back());
+        assert_eq!(&test_vm.memory()[32..], &[0; 16]);
+
+        test_vm.registers[0] = 40;
+        test_vm.set_pc(4);
+        assert_eq!(test_vm.run_once(), Err(10));
+    }
+
+    #[test]
+    fn test_truncated_instruction() {
+        let mut test_vm = VM::builder().quiet().build();
+        test_vm.program = vec![1, 0, 1];
+        assert_eq!(test_vm.run_once(), Err(16));
+        assert_eq!(test_vm.pc(), 3);
+        assert_eq!(test_vm.error_message(16), "truncated instruction at pc 0");
+    }
+
+    #[test]
+    fn test_console_io() {
+        let output = Buffer::default();
+        let input = "17\n  -5 \nhello world\n";
+        let mut test_vm = VM::builder()
+            .memory_size(9)
+            .input(Box::new(input.as_bytes()))
+            .output(Box::new(output.clone()))
+            .quiet()
+            .build();
+        let source = "load $0 @greeting\nprts $0\nrdi $1\nrdi $2\nadd $1 $2 $3\nprti $3\n\
+            load $4 #10\nprtc $4\nload $5 #6\nrds $5 $6\nrds $5 $6\nhlt\n\
+            greeting: .asciiz 'sum: '\n";
+        test_vm
+            .load(crate::assembler::assemble("io.s", source).unwrap())
+            .unwrap();
+        assert_eq!(&test_vm.memory()[..6], b"sum: \0");
+        test_vm.registers[6] = 3;
+        for _ in 0..9 {
+            assert_eq!(test_vm.run_once(), Ok(Step::Continue));
+        }
+        assert_eq!(
+            String::from_utf8(output.0.lock().unwrap().clone()).unwrap(),
+            "sum: 12\n"
+        );
+        assert_eq!(test_vm.run_once(), Ok(Step::Continue));
+        assert!(test_vm.equal_flag());
+        assert_eq!(&test_vm.memory()[6..], b"he\0");
+        assert_eq!(test_vm.run_once(), Ok(Step::Continue));
+        assert!(!test_vm.equal_flag());
+        assert_eq!(&test_vm.memory()[6..], b"\0e\0");
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+    }
+
+    #[test]
+    fn test_console_io_errors() {
+        let mut test_vm = VM::builder()
+            .memory_size(4)
+            .input(Box::new(&b"abc\n"[..]))
+            .output(Box::new(Buffer::default()))
+            .quiet()
+            .build();
+        // PRTS $0, RDI $1, RDS $0 $2
+        test_vm.program = vec![0x16, 0, 0x17, 1, 0x18, 0, 2];
+        test_vm.memory = vec![b'x'; 4];
+        assert_eq!(test_vm.run_once(), Err(10));
+        assert_eq!(test_vm.run_once(), Err(11));
+        assert_eq!(test_vm.error_message(11), "invalid input at pc 2");
+        test_vm.registers[2] = 5;
+        assert_eq!(test_vm.run_once(), Err(10));
+    }
+
+    #[test]
+    fn test_step_back_memory() {
+        let mut test_vm = VM::builder()
+            .memory_size(4)
+            .input(Box::new(&b"ab\n"[..]))
+            .quiet()
+            .build();
+        // RDS $0 $1
+        test_vm.program = vec![0x18, 0, 1];
+        test_vm.registers[1] = 4;
+        test_vm.set_recording(true);
+        assert_eq!(test_vm.run_once(), Ok(Step::Continue));
+        assert_eq!(test_vm.memory(), b"ab\0\0");
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.memory(), &[0; 4]);
+    }
+
+    #[test]
+    fn test_run_with_budget() {
+        let costs = CostTable::new().set(&Opcode::DIV, 10);
+        let mut test_vm = VM::builder().costs(costs).quiet().build();
+        // load $0 #8, load $1 #2, div $0 $1 $2, hlt
+        test_vm.program = vec![1, 0, 0, 8, 1, 1, 0, 2, 5, 0, 1, 2, 0];
+        assert_eq!(test_vm.run_with_budget(11), Ok(Step::BudgetExhausted));
+        assert_eq!(test_vm.pc(), 8);
+        assert_eq!(test_vm.consumed(), 2);
+        assert_eq!(test_vm.run_with_budget(10), Ok(Step::BudgetExhausted));
+        assert_eq!(test_vm.registers[2], 4);
+        assert_eq!(test_vm.run_with_budget(1), Ok(Step::Done));
+        assert_eq!(test_vm.consumed(), 13);
+        assert_eq!(CostTable::new().cost(200), 1);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let mut test_vm = VM::builder().memory_size(4).quiet().build();
+        test_vm
+            .load(
+                crate::assembler::assemble("loop.s", "load $0 #7\nload $1 #7\neq $0 $1\nhlt\n")
+                    .unwrap(),
+            )
+            .unwrap();
+        test_vm.memory[2] = 9;
+        test_vm.registers[31] = -5;
+        test_vm.float_registers[2] = -0.25;
+        test_vm.vector_registers[15] = [1, -2, 3, -4];
+        for _ in 0..3 {
+            test_vm.run_once().unwrap();
+        }
+        let snapshot = test_vm.snapshot();
+
+        let mut restored = VM::builder().quiet().build();
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.pc(), 11);
+        assert!(restored.equal_flag());
+        assert_eq!(restored.executed(), 3);
+        assert_eq!(restored.registers(), test_vm.registers());
+        assert_eq!(restored.float_registers()[2], -0.25);
+        assert_eq!(restored.vector_registers()[15], [1, -2, 3, -4]);
+        assert_eq!(restored.memory(), &[0, 0, 9, 0]);
+        assert_eq!(restored.program(), test_vm.program());
+        assert_eq!(restored.location(11), "loop.s:4");
+        assert_eq!(restored.run(), Ok(Step::Done));
+        assert_eq!(restored.snapshot()[..5], snapshot[..5]);
+
+        let mut bad = snapshot.clone();
+        bad[4] = 9;
+        assert_eq!(
+            restored.restore(&bad),
+            Err(ImageError::UnsupportedVersion(9))
+        );
+        assert_eq!(
+            restored.restore(&snapshot[..snapshot.len() - 1]),
+            Err(ImageError::Truncated)
+        );
+        assert_eq!(restored.pc(), 12);
+
+        // a restored machine doesn't keep the cost and status of its previous run
+        let mut test_vm = VM::builder()
+            .costs(CostTable::new().set(&Opcode::ADD, 10))
+            .quiet()
+            .build();
+        test_vm
+            .load(
+                crate::assembler::assemble("exit.s", "load $0 #3\nadd $0 $0 $0\nexit $0\n")
+                    .unwrap(),
+            )
+            .unwrap();
+        let before = test_vm.snapshot();
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!((test_vm.consumed(), test_vm.exit_code()), (12, 6));
+        let after = test_vm.snapshot();
+        test_vm.restore(&before).unwrap();
+        assert_eq!(test_vm.executed(), 0);
+        assert_eq!((test_vm.consumed(), test_vm.exit_code()), (0, 0));
+        test_vm.restore(&after).unwrap();
+        assert_eq!((test_vm.consumed(), test_vm.exit_code()), (12, 6));
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut test_vm = VM::new();
+        test_vm.set_recording(true);
+        // LOAD $0 #500; LOAD $1 #501; DIV $1 $0 $2; NEQ $0 $1; HLT
+        test_vm.program = vec![1, 0, 1, 244, 1, 1, 1, 245, 5, 1, 0, 2, 0xB, 0, 1, 0];
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!(test_vm.history_len(), 5);
+        assert!(test_vm.equal_flag);
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.pc, 15);
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.pc, 12);
+        assert!(!test_vm.equal_flag);
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.pc, 8);
+        assert_eq!(test_vm.registers[2], 0);
+        assert_eq!(test_vm.remainder, 0);
+        assert!(test_vm.step_back());
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.pc, 0);
+        assert_eq!(test_vm.registers[..3], [0, 0, 0]);
+        assert!(!test_vm.step_back());
+
+        // replaying gives the same result
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!(test_vm.registers[2], 1);
+        assert_eq!(test_vm.remainder, 1);
+        test_vm.set_recording(false);
+        assert_eq!(test_vm.history_len(), 0);
+    }
+
+    #[test]
+    fn test_history_limit() {
+        let mut test_vm = VM::new();
+        test_vm.set_recording(true);
+        test_vm.set_history_limit(2);
+        // LOAD $0 #1; LOAD $1 #2; LOAD $2 #3; EXIT $2
+        test_vm.program = vec![1, 0, 0, 1, 1, 1, 0, 2, 1, 2, 0, 3, 0x19, 2];
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!(test_vm.history_len(), 2);
+        assert_eq!((test_vm.exit_code(), test_vm.consumed()), (3, 4));
+        assert!(test_vm.step_back());
+        assert_eq!((test_vm.exit_code(), test_vm.consumed()), (0, 3));
+        assert!(test_vm.step_back());
+        assert_eq!(test_vm.registers[..3], [1, 2, 0]);
+        assert!(!test_vm.step_back());
+        assert_eq!(test_vm.pc, 8);
+
+        test_vm.set_history_limit(0);
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!(test_vm.history_len(), 0);
+    }
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        use crate::trace::TraceFormat;
+
+        let buffer = Buffer::default();
+        let mut test_vm = VM::new();
+        test_vm.set_tracer(Some(Tracer::new(
+            TraceFormat::Text,
+            Box::new(buffer.clone()),
+        )));
+        // LOAD $0 #500; LOAD $1 #501; DIV $1 $0 $2; NEQ $0 $1; HLT
+        test_vm.program = vec![1, 0, 1, 244, 1, 1, 1, 245, 5, 1, 0, 2, 0xB, 0, 1, 0];
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert_eq!(
+            String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap(),
+            "0x0000 LOAD $0 #500     $0: 0 -> 500
+0x0004 LOAD $1 #501     $1: 0 -> 501
+0x0008 DIV $1 $0 $2     $2: 0 -> 1 rem: 0 -> 1
+0x000C NEQ $0 $1        eq: false -> true
+0x000F HLT
+"
+        );
+
+        struct Closed;
+        impl Write for Closed {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        test_vm.set_tracer(Some(Tracer::new(TraceFormat::Text, Box::new(Closed))));
+        test_vm.pc = 0;
+        assert_eq!(test_vm.run(), Ok(Step::Done));
+        assert!(!test_vm.is_tracing());
+    }
+
+    #[test]
+    fn test_trace_vector_and_memory_writes() {
+        use crate::trace::TraceFormat;
+
+        let buffer = Buffer::default();
+        let mut test_vm = VM::builder().memory_size(32).quiet().build();
+        test_vm.set_tracer(Some(Tracer::new(
+            TraceFormat::Text,
+            Box::new(buffer.clone()),
+        )));
+        test_vm
+            .load(
+                crate::assembler::assemble("vec.s", "vld $0 $v1\nload $2 #16\nvst $v1 $2\n")
+                    .unwrap(),
+            )
+            .unwrap();
+        test_vm.memory[3] = 7;
+        test_vm.add_watchpoint(Register::Vector(1), WatchKind::Change);
+        assert_eq!(
+            test_vm.run(),
+            Ok(Step::Watchpoint(WatchHit::Register {
+                register: Register::Vector(1),
+                old: Value::Vector([0; 4]),
+                new: Value::Vector([7, 0, 0, 0]),
+                pc: 0,
+            }))
+        );
+        assert_eq!(test_vm.run(), Err(1));
+        assert_eq!(&test_vm.memory()[16..20], &[0, 0, 0, 7]);
+        let trace = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(
+            lines[0],
+            "0x0000 VLD $0 $v1       $v1: [0, 0, 0, 0] -> [7, 0, 0, 0]"
+        );
+        assert_eq!(
+            lines[2],
+            "0x0007 VST $v1 $2       mem[0x0010]: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 \
+             -> 00 00 00 07 00 00 00 00 00 00 00 00 00 00 00 00"
+        );
+    }
+
+    #[test]
+    fn test_trace_float_write() {
+        use crate::trace::TraceFormat;
+
+        let buffer = Buffer::default();
+        let mut test_vm = VM::new();
+        test_vm.set_tracer(Some(Tracer::new(
+            TraceFormat::JsonLines,
+            Box::new(buffer.clone()),
+        )));
+        test_vm
+            .load(crate::assembler::assemble("float.s", "loadf $f1 #2.5\n").unwrap())
+            .unwrap();
+        assert!(matches!(test_vm.run_once(), Ok(Step::Continue)));
+        assert!(String::from_utf8(buffer.0.lock().unwrap().clone())
+            .unwrap()
+            .ends_with(
+                r#""writes":[{"float_register":1,"old":0,"new":2.5}]}
+"#
+            ));
+    }
+
     #[test]
     fn test_jmp_opcode() {
         let mut test_vm = VM::new();